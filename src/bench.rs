@@ -0,0 +1,541 @@
+use crate::bplus::{BPTree, BlockId, Data, Entry, InsertResult, Key, LEASE_TTL};
+use crate::error::NodeError;
+use crate::events::handle_lease_request;
+use crate::migration::Migrations;
+use crate::network::Client;
+use crate::GeneralResponse;
+use libp2p::core::PeerId;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//inserts from several threads into one in-memory tree, each thread in its own key range so they touch different leaves.
+//the tree is bulk loaded with the even keys of every range first and the threads insert the odd ones, so the inserts
+//spread over the leaves. the same inserts run once with the whole tree behind one lock, as main used to hold it,
+//and once with the block latches alone
+pub struct BenchResult {
+    pub threads: usize,
+    pub inserts: usize, //per thread
+    pub global_lock: Duration,
+    pub latched: Duration,
+}
+impl BenchResult {
+    pub fn ops_per_sec(&self, elapsed: Duration) -> f64 {
+        (self.threads * self.inserts) as f64 / elapsed.as_secs_f64()
+    }
+}
+
+pub fn run(
+    threads: usize,
+    inserts: usize,
+    leaf_order: usize,
+    internal_order: usize,
+) -> Result<BenchResult, NodeError> {
+    let tree = Arc::new(RwLock::new(loaded_tree(
+        threads,
+        inserts,
+        leaf_order,
+        internal_order,
+    )?));
+    let global_lock = time_inserts(threads, inserts, move |key, entry| {
+        let tree = tree.write().unwrap(); //held for the whole insert and split, like the lease handler did
+        insert_key(&tree, key, entry)
+    })?;
+    let tree = Arc::new(loaded_tree(threads, inserts, leaf_order, internal_order)?);
+    let latched = time_inserts(threads, inserts, move |key, entry| {
+        insert_key(&tree, key, entry)
+    })?;
+    Ok(BenchResult {
+        threads,
+        inserts,
+        global_lock,
+        latched,
+    })
+}
+
+fn loaded_tree(
+    threads: usize,
+    inserts: usize,
+    leaf_order: usize,
+    internal_order: usize,
+) -> Result<BPTree, NodeError> {
+    let tree = BPTree::new(leaf_order, internal_order);
+    let holder = PeerId::random();
+    let entries = (0..threads as u64).flat_map(|range| {
+        (0..inserts as u64).map(move |i| {
+            let key = bench_key(range, 2 * i);
+            (key, Entry::new(holder, key, Data::empty(), LEASE_TTL))
+        })
+    });
+    tree.bulk_load(entries)?;
+    Ok(tree)
+}
+
+fn bench_key(range: u64, i: u64) -> Key {
+    (range << 32) | i
+}
+
+fn time_inserts<F>(threads: usize, inserts: usize, insert: F) -> Result<Duration, NodeError>
+where
+    F: Fn(Key, Entry) -> Result<(), NodeError> + Send + Sync + 'static,
+{
+    let insert = Arc::new(insert);
+    let holder = PeerId::random();
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads as u64)
+        .map(|range| {
+            let insert = insert.clone();
+            thread::spawn(move || -> Result<(), NodeError> {
+                for i in 0..inserts as u64 {
+                    let key = bench_key(range, 2 * i + 1);
+                    insert(key, Entry::new(holder, key, Data::empty(), LEASE_TTL))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("bench thread panicked")?;
+    }
+    Ok(start.elapsed())
+}
+
+//inserts a key and carries the splits up to the local parents, like the lease handler
+fn insert_key(tree: &BPTree, key: Key, entry: Entry) -> Result<(), NodeError> {
+    let leaf_id = tree
+        .locate(tree.get_top_id(), &key)
+        .map_err(NodeError::MissingBlock)?;
+    let mut result = tree.insert(leaf_id, key, entry)?;
+    while let InsertResult::RightBlock(right_id, divider_key) = result {
+        let parent = tree.get_block(right_id)?.parent();
+        if parent == 0 {
+            break;
+        }
+        result = tree.insert_child(divider_key, right_id, parent)?.1;
+    }
+    Ok(())
+}
+
+const STRESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct StressResult {
+    pub granted: usize,
+    pub failed: usize, //denied, answered with an error or the task panicked
+    pub hung: usize,   //no answer within the timeout
+}
+
+//sends concurrent lease requests through the handler on this peer, so blocks split and migrate while other
+//requests are queued on them, and counts the requests that do not finish in time
+pub async fn stress(
+    requests: usize,
+    bp_tree: Arc<BPTree>,
+    client: Client,
+    holder: PeerId,
+    migrate_peer: PeerId,
+    migrations: Migrations,
+    top_id: BlockId,
+) -> StressResult {
+    let tasks: Vec<_> = (0..requests as u64)
+        .map(|i| {
+            let key = i.wrapping_mul(0x9e3779b97f4a7c15) >> 16; //spread the keys over the leaves
+            let entry = Entry::new(holder, key, Data::empty(), LEASE_TTL);
+            let (bp_tree, migrations, mut client) =
+                (bp_tree.clone(), migrations.clone(), client.clone());
+            tokio::spawn(async move {
+                let request = handle_lease_request(
+                    key,
+                    entry,
+                    bp_tree,
+                    &mut client,
+                    migrate_peer,
+                    migrations,
+                    top_id,
+                );
+                tokio::time::timeout(STRESS_TIMEOUT, request).await
+            })
+        })
+        .collect();
+    let mut result = StressResult {
+        granted: 0,
+        failed: 0,
+        hung: 0,
+    };
+    for task in tasks {
+        match task.await {
+            Ok(Ok(response)) => match response.result() {
+                GeneralResponse::LeaseGranted(_) => result.granted += 1,
+                _ => result.failed += 1,
+            },
+            Ok(Err(_)) => result.hung += 1,
+            Err(_) => result.failed += 1,
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bplus::Block;
+    use crate::events::{
+        check_tree, handle_abort_migration, handle_commit_migration,
+        handle_insert_on_remote_parent, handle_migrate, handle_migrate_blocks, handle_set_parent,
+        resume_handoffs,
+    };
+    use crate::network;
+    use crate::GeneralRequest;
+    use futures::prelude::*;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+
+    //peers in one process, each with its own tree and migrations, that reach each other through in-process clients
+    struct Net {
+        peers: Vec<(PeerId, Arc<BPTree>, Migrations)>,
+        providers: Arc<Mutex<HashMap<String, HashSet<PeerId>>>>,
+        faults: Mutex<Vec<Fault>>, //each used up by the next commit sent between the peers
+    }
+    #[derive(PartialEq)]
+    enum Fault {
+        FailCommit,         //the receiver is unreachable when the commit arrives
+        LoseCommitResponse, //the receiver handles the commit but its answer never reaches the sender
+    }
+    impl Net {
+        fn new(peers: usize) -> Arc<Self> {
+            let peers = (0..peers)
+                .map(|_| {
+                    let peer = PeerId::random();
+                    let mut tree = BPTree::new(4, 4);
+                    tree.set_creator(&peer);
+                    (peer, Arc::new(tree), Migrations::new())
+                })
+                .collect();
+            Arc::new(Self {
+                peers,
+                providers: Arc::new(Mutex::new(HashMap::new())),
+                faults: Mutex::new(Vec::new()),
+            })
+        }
+        fn inject(&self, fault: Fault) {
+            self.faults.lock().unwrap().push(fault);
+        }
+        fn client(self: &Arc<Self>, peer: PeerId) -> Client {
+            let net = self.clone();
+            let handler: network::Handler =
+                Arc::new(move |to, request| net.clone().dispatch(to, request).boxed());
+            network::in_process(peer, self.providers.clone(), handler)
+        }
+        //handles a request on a peer like the event loop of main does
+        async fn dispatch(
+            self: Arc<Self>,
+            to: PeerId,
+            request: String,
+        ) -> Result<String, NodeError> {
+            let i = self
+                .peers
+                .iter()
+                .position(|(peer, _, _)| *peer == to)
+                .unwrap();
+            let (bp_tree, migrations) = (self.peers[i].1.clone(), self.peers[i].2.clone());
+            let migrate_peer = self.peers[(i + 1) % self.peers.len()].0;
+            let mut client = self.client(to);
+            let request: GeneralRequest = serde_json::from_str(&request).unwrap();
+            let fault = match request {
+                GeneralRequest::CommitMigration(..) => self.faults.lock().unwrap().pop(),
+                _ => None,
+            };
+            if fault == Some(Fault::FailCommit) {
+                return Err(NodeError::PeerUnreachable(to));
+            }
+            let request = match request {
+                GeneralRequest::Routed {
+                    generation,
+                    request,
+                } => {
+                    let stale = request
+                        .route()
+                        .and_then(|(_, block_id)| bp_tree.get_block(block_id).ok())
+                        .filter(|block| block.generation() > generation);
+                    if let Some(block) = stale {
+                        let redirect: GeneralResponse = GeneralResponse::Redirect(block.meta());
+                        return Ok(serde_json::to_string(&redirect).unwrap());
+                    }
+                    *request
+                }
+                request => request,
+            };
+            let missing = request.target().filter(|id| {
+                if *id == 0 {
+                    !bp_tree.is_root()
+                } else {
+                    !bp_tree.is_local(*id)
+                }
+            });
+            let response: GeneralResponse = match (missing, request) {
+                (Some(id), _) => GeneralResponse::Error(NodeError::MissingBlock(id)),
+                (None, GeneralRequest::LeaseRequest(key, entry, block_id)) => {
+                    let block_id = if block_id == 0 {
+                        bp_tree.get_top_id()
+                    } else {
+                        block_id
+                    }; //the default id stands for the top block
+                    handle_lease_request(
+                        key,
+                        entry,
+                        bp_tree,
+                        &mut client,
+                        migrate_peer,
+                        migrations,
+                        block_id,
+                    )
+                    .await
+                }
+                (None, GeneralRequest::InsertOnRemoteParent(key, parent, child)) => {
+                    handle_insert_on_remote_parent(
+                        key,
+                        parent,
+                        child,
+                        bp_tree,
+                        &mut client,
+                        migrate_peer,
+                        migrations,
+                    )
+                    .await
+                }
+                (None, GeneralRequest::SetParent(child, expected, parent)) => {
+                    handle_set_parent(child, expected, parent, bp_tree, migrations).await
+                }
+                (None, GeneralRequest::MigrateRequest(block)) => {
+                    handle_migrate(block, bp_tree, &mut client).await
+                }
+                (None, GeneralRequest::CommitMigration(id, is_top)) => {
+                    handle_commit_migration(id, is_top, bp_tree, &mut client).await
+                }
+                (None, GeneralRequest::AbortMigration(id)) => {
+                    handle_abort_migration(id, bp_tree, &mut client).await
+                }
+                (None, GeneralRequest::GetBlock(id)) => match bp_tree.get_block(id) {
+                    Ok(block) => GeneralResponse::BlockFound(block),
+                    Err(err) => GeneralResponse::Error(err),
+                },
+                (None, request) => GeneralResponse::Error(NodeError::Codec(format!(
+                    "unexpected request {:?}",
+                    request
+                ))),
+            };
+            if fault == Some(Fault::LoseCommitResponse) {
+                return Err(NodeError::Timeout(to));
+            }
+            Ok(serde_json::to_string(&response).unwrap())
+        }
+    }
+
+    //a root split into two leaves on the peer, returns the left and the right leaf
+    fn split_root(bp_tree: &BPTree, peer: PeerId) -> (BlockId, BlockId) {
+        let mut block = Block::new(bp_tree.leaf_order());
+        block.set_block_id(bp_tree.next_block_id().unwrap());
+        let top_id = block.return_id();
+        bp_tree.add_block(top_id, block).unwrap();
+        bp_tree.set_top_id(top_id).unwrap();
+        for key in [10, 20, 30, 40] {
+            bp_tree
+                .insert(top_id, key, Entry::new(peer, key, Data::empty(), LEASE_TTL))
+                .unwrap();
+        }
+        let top_id = bp_tree.get_top_id();
+        (
+            bp_tree.locate(top_id, &10).unwrap(),
+            bp_tree.locate(top_id, &40).unwrap(),
+        )
+    }
+    fn providers(net: &Net, id: BlockId) -> HashSet<PeerId> {
+        net.providers
+            .lock()
+            .unwrap()
+            .get(&id.to_string())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    //concurrent lease requests split the blocks of the first peer and migrate the right halves to the second one
+    //while other requests queue on them. every request has to be answered and the tree has to stay valid
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_inserts_and_migrations() {
+        let net = Net::new(2);
+        let (peer, bp_tree, migrations) = net.peers[0].clone();
+        let mut block = Block::new(bp_tree.leaf_order());
+        block.set_block_id(bp_tree.next_block_id().unwrap());
+        let top_id = block.return_id();
+        bp_tree.add_block(top_id, block).unwrap();
+        bp_tree.set_top_id(top_id).unwrap();
+        let requests = 200;
+        let mut client = net.client(peer);
+        client.start_providing(top_id.to_string()).await;
+        client.boot_root().await;
+        let result = stress(
+            requests,
+            bp_tree.clone(),
+            client,
+            peer,
+            net.peers[1].0,
+            migrations,
+            top_id,
+        )
+        .await;
+        assert_eq!(result.hung, 0);
+        assert_eq!(result.granted, requests);
+        assert!(net.peers[1].1.get_size() > 0); //blocks did migrate
+                                                //parents a split moved while they waited on another request are still retried in the background
+        let mut violations = Vec::new();
+        for _ in 0..10 {
+            violations = check_tree(bp_tree.clone(), &mut net.client(peer), bp_tree.get_top_id())
+                .await
+                .unwrap();
+            if violations.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert!(violations.is_empty(), "{:?}", violations);
+    }
+
+    //a peer that knows no other peer yet migrates to itself, so the blocks it splits off stay here unfrozen
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn splits_stay_on_a_peer_without_other_peers() {
+        let net = Net::new(1);
+        let (peer, bp_tree, migrations) = net.peers[0].clone();
+        let mut block = Block::new(bp_tree.leaf_order());
+        block.set_block_id(bp_tree.next_block_id().unwrap());
+        let top_id = block.return_id();
+        bp_tree.add_block(top_id, block).unwrap();
+        bp_tree.set_top_id(top_id).unwrap();
+        let requests = 50;
+        let result = stress(
+            requests,
+            bp_tree.clone(),
+            net.client(peer),
+            peer,
+            peer,
+            migrations.clone(),
+            top_id,
+        )
+        .await;
+        assert_eq!(result.hung, 0);
+        assert_eq!(result.granted, requests);
+        assert!(bp_tree.get_size() > 1); //blocks did split
+        assert!(bp_tree.sending().is_empty());
+        assert!(migrations.blocks().is_empty());
+        assert!(bp_tree.check_invariants().is_empty());
+    }
+
+    //a lease asked for on a peer without the root goes to the root providers and skips one that lost the root
+    #[tokio::test]
+    async fn lease_from_another_peer_skips_a_stale_root_provider() {
+        let net = Net::new(2);
+        let (root_peer, root_tree, _) = net.peers[0].clone();
+        let (peer, bp_tree, migrations) = net.peers[1].clone();
+        let mut block = Block::new(root_tree.leaf_order());
+        block.set_block_id(root_tree.next_block_id().unwrap());
+        let top_id = block.return_id();
+        root_tree.add_block(top_id, block).unwrap();
+        root_tree.set_top_id(top_id).unwrap();
+        net.client(root_peer).boot_root().await;
+        net.client(peer).boot_root().await; //a record left behind by a peer that no longer holds the root
+        let entry = Entry::new(peer, 7, Data::empty(), LEASE_TTL);
+        let mut client = net.client(peer);
+        let response =
+            handle_lease_request(7, entry, bp_tree, &mut client, root_peer, migrations, 0).await;
+        assert!(matches!(response.result(), GeneralResponse::LeaseGranted(id) if id == top_id));
+        assert_eq!(root_tree.get(&7).map(|entry| entry.holder()), Some(peer));
+    }
+
+    //the receiver prepares the block but fails before the commit, so the sender aborts, the receiver drops its
+    //frozen copy and the sender serves the block again
+    #[tokio::test]
+    async fn failed_commit_is_aborted_and_the_block_thawed() {
+        let net = Net::new(2);
+        let (peer, bp_tree, migrations) = net.peers[0].clone();
+        let (receiver, receiver_tree, _) = net.peers[1].clone();
+        let (_, right_id) = split_root(&bp_tree, peer);
+        net.inject(Fault::FailCommit);
+        let mut client = net.client(peer);
+        let moved = handle_migrate_blocks(
+            right_id,
+            false,
+            &mut client,
+            receiver,
+            bp_tree.clone(),
+            migrations.clone(),
+        );
+        assert_eq!(moved.await.unwrap(), Vec::<BlockId>::new());
+        assert!(bp_tree.contains(right_id));
+        assert!(!bp_tree.is_frozen(right_id));
+        assert!(bp_tree.sending().is_empty());
+        assert!(!receiver_tree.contains(right_id));
+        assert!(!providers(&net, right_id).contains(&receiver));
+        let result = bp_tree
+            .insert(right_id, 35, Entry::new(peer, 35, Data::empty(), LEASE_TTL))
+            .unwrap();
+        assert!(matches!(result, InsertResult::Complete));
+        assert!(bp_tree.check_invariants().is_empty());
+    }
+
+    //the receiver takes the block over but the sender never hears of it, the abort is then answered with migrated
+    //and the sender drops its copy, so the block is not owned twice
+    #[tokio::test]
+    async fn lost_commit_answer_is_settled_by_the_abort() {
+        let net = Net::new(2);
+        let (peer, bp_tree, migrations) = net.peers[0].clone();
+        let (receiver, receiver_tree, _) = net.peers[1].clone();
+        let (_, right_id) = split_root(&bp_tree, peer);
+        net.inject(Fault::LoseCommitResponse);
+        let mut client = net.client(peer);
+        let moved = handle_migrate_blocks(
+            right_id,
+            false,
+            &mut client,
+            receiver,
+            bp_tree.clone(),
+            migrations.clone(),
+        );
+        assert_eq!(moved.await.unwrap(), vec![right_id]);
+        assert!(!bp_tree.contains(right_id));
+        assert!(bp_tree.sending().is_empty());
+        assert!(receiver_tree.contains(right_id));
+        assert!(!receiver_tree.is_frozen(right_id));
+        assert_eq!(providers(&net, right_id), HashSet::from([receiver]));
+        let block = receiver_tree.get_block(right_id).unwrap();
+        assert_eq!(block.get_entry(&40).map(|entry| entry.holder()), Some(peer));
+    }
+
+    //handoffs logged before a restart are decided by asking the receiver: a block it only prepared comes back,
+    //a block it already took over is dropped here
+    #[tokio::test]
+    async fn resumed_handoffs_keep_one_owner_per_block() {
+        let net = Net::new(2);
+        let (peer, bp_tree, migrations) = net.peers[0].clone();
+        let (receiver, receiver_tree, _) = net.peers[1].clone();
+        let (left_id, right_id) = split_root(&bp_tree, peer);
+        let mut receiver_client = net.client(receiver);
+        for id in [left_id, right_id] {
+            let block = bp_tree.freeze(id, receiver).unwrap();
+            let response = handle_migrate(block, receiver_tree.clone(), &mut receiver_client).await;
+            assert!(matches!(response, GeneralResponse::Prepared(prepared) if prepared == id));
+        }
+        let response =
+            handle_commit_migration(right_id, false, receiver_tree.clone(), &mut receiver_client)
+                .await;
+        assert!(matches!(response, GeneralResponse::Migrated(_)));
+        //the sender stopped before either commit was answered, as a reopened tree finds its handoffs
+        resume_handoffs(&mut net.client(peer), bp_tree.clone(), migrations.clone()).await;
+        assert!(bp_tree.sending().is_empty());
+        assert!(migrations.blocks().is_empty());
+        assert!(bp_tree.contains(left_id));
+        assert!(!bp_tree.is_frozen(left_id));
+        assert!(!receiver_tree.contains(left_id));
+        assert!(!bp_tree.contains(right_id));
+        assert!(receiver_tree.contains(right_id));
+        assert!(!receiver_tree.is_frozen(right_id));
+        assert!(providers(&net, left_id).contains(&peer));
+        assert!(!providers(&net, left_id).contains(&receiver));
+    }
+}
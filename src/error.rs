@@ -0,0 +1,57 @@
+use crate::bplus::BlockId;
+use libp2p::core::PeerId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Hash)]
+pub enum NodeError {
+    MissingBlock(BlockId),         //the block is not in the local block map
+    BlockMigrating(BlockId),       //the block is being moved to another peer
+    NoProvider(String),            //no peer advertises the key on the DHT
+    PeerUnreachable(PeerId),       //the request could not be delivered to the peer
+    Codec(String),                 //a message could not be encoded or decoded
+    Timeout(PeerId),               //the peer did not answer in time
+    Storage(String),               //the block map could not be read from or written to disk
+    PayloadTooLarge(usize, usize), //size of the rejected payload and the limit of the peer
+    NotEmpty(BlockId),             //a bulk load needs a tree without entries, holds the top id
+    Unsorted(usize), //position of a bulk loaded key that does not follow the one before it
+    ParentMoved(BlockId, BlockId), //the block points to another parent than the update expects, holds that parent
+    IdsExhausted(u64),             //the block id counter of this peer ran out, holds the id prefix
+    PrefixTaken(PeerId), //another peer creates block ids with the prefix of this one, holds that peer
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeError::MissingBlock(id) => write!(f, "block {} is not held by this peer", id),
+            NodeError::BlockMigrating(id) => write!(f, "block {} is being migrated", id),
+            NodeError::NoProvider(key) => write!(f, "could not find provider for {}", key),
+            NodeError::PeerUnreachable(peer) => write!(f, "peer {} is unreachable", peer),
+            NodeError::Codec(reason) => write!(f, "codec error: {}", reason),
+            NodeError::Timeout(peer) => write!(f, "request to peer {} timed out", peer),
+            NodeError::Storage(reason) => write!(f, "storage error: {}", reason),
+            NodeError::PayloadTooLarge(size, limit) => {
+                write!(
+                    f,
+                    "payload of {} bytes exceeds the limit of {} bytes",
+                    size, limit
+                )
+            }
+            NodeError::NotEmpty(id) => {
+                write!(f, "tree with top block {} already holds entries", id)
+            }
+            NodeError::Unsorted(index) => write!(f, "key at position {} is out of order", index),
+            NodeError::ParentMoved(id, parent) => {
+                write!(f, "block {} points to parent {}", id, parent)
+            }
+            NodeError::IdsExhausted(prefix) => {
+                write!(f, "no block ids left under prefix {:08x}", prefix)
+            }
+            NodeError::PrefixTaken(peer) => {
+                write!(f, "peer {} creates block ids with the same prefix", peer)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
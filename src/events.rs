@@ -1,265 +1,1219 @@
 use super::*;
-use bplus::{BPTree, Block, BlockId, Entry, InsertResult, Key};
+use bplus::{
+    check_blocks, BPTree, Block, BlockId, BlockMeta, CasResult, Entry, InsertResult, TreeKey,
+    TreeValue, Violation,
+};
 use libp2p::core::PeerId;
 use libp2p::request_response::ResponseChannel;
+use migration::Migrations;
 use network::{Client, GenericResponse};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-pub async fn handle_lease_request(
-    key: Key,
-    entry: Entry,
-    bp_tree: Arc<RwLock<BPTree>>,
+pub async fn handle_lease_request<K: TreeKey, V: TreeValue>(
+    key: K,
+    entry: Entry<K, V>,
+    bp_tree: Arc<BPTree<K, V>>,
     client: &mut Client,
     migrate_peer: PeerId,
-    migrating_block: Arc<RwLock<HashSet<BlockId>>>,
-    queries: Arc<RwLock<HashMap<BlockId, Vec<GeneralRequest>>>>,
+    migrations: Migrations<K, V>,
     block_id: BlockId,
-) {
-    
-    let current_id = {
-        let bp_tree = bp_tree.read().unwrap();
-        bp_tree.find(block_id, key) //read operation
+) -> GeneralResponse<K, V> {
+    let current_id = match bp_tree.find(block_id, &key) {
+        //read operation
+        Ok(current_id) => current_id,
+        Err(NodeError::MissingBlock(id)) if id == block_id => block_id, //forwarded to the providers of the block
+        Err(err) => return GeneralResponse::Error(err),
     };
-    
-    let read_migrating_block = migrating_block.read().unwrap();
-    
-    if read_migrating_block.contains(&current_id) {
-        let request = GeneralRequest::LeaseRequest(key, entry,current_id);
-        let mut queries = queries.write().unwrap();
-        if queries.contains_key(&current_id) {
-            let requests = queries.get_mut(&current_id).unwrap();
-            requests.push(request);
-        } else {
-            let requests = vec![request];
-            queries.insert(current_id, requests);
+
+    let request = GeneralRequest::LeaseRequest(key.clone(), entry.clone(), current_id);
+    let response = if let Some(receiver) = migrations.queue(current_id, request) {
+        //the request is answered once it has been flushed to the new provider
+        match receiver.await {
+            Ok(response) => response,
+            Err(_) => GeneralResponse::Error(NodeError::BlockMigrating(current_id)),
         }
     } else {
-        drop(read_migrating_block);
-        let current_block = {
-            let bp_tree = bp_tree.read().unwrap();
-            bp_tree.get_block(current_id)
-        }; //returns either the leaf or the internal block of the local b-plus tree
-       
-        if current_block.is_leaf() {
-            
-            if key >= current_block.return_divider_key() {
-                //if the key does not belong in this leaf block
-                let next_block_id = current_block.return_next_block();
-                let request = GeneralRequest::LeaseRequest(key, entry,next_block_id);
-                let peers = client.get_providers(next_block_id.to_string()).await;
-                let _requests = peers.into_iter().map(|p| {
-                    let mut network_client = client.clone();
-                    let request = request.clone();
-                    async move { network_client.request(p, request).await }.boxed()
-                    //flush the query in the next block
-                });
-            } else {
-                
-                let mut write_bp_tree = bp_tree.write().unwrap();
-                let result = write_bp_tree.insert(current_id, key, entry); //if the block is a leaf then add the entry (write operation)
-                drop(write_bp_tree);
+        //find returns a leaf of the local b-plus tree if this peer holds it, otherwise the block to forward to
+        match bp_tree.is_local(current_id) {
+            true => {
+                //only the leaf is latched, inserts into other leaves of this peer run alongside
+                let top_id = bp_tree.get_top_id();
+                let mut retries = 0;
+                let result = loop {
+                    let (insert_key, insert_entry) = (key.clone(), entry.clone());
+                    //if the block is a leaf then add the entry (write operation)
+                    match blocking(&bp_tree, move |tree| {
+                        tree.insert(current_id, insert_key, insert_entry)
+                    })
+                    .await
+                    {
+                        //frozen since the queue was checked, or still being received. once the handoff is decided the
+                        //leaf is either here again or missing, and the request is forwarded to its new peer
+                        Err(NodeError::BlockMigrating(_)) if retries < SPLIT_RETRIES => {
+                            retries += 1;
+                            tokio::time::sleep(SPLIT_RETRY_DELAY).await;
+                        }
+                        result => break result,
+                    }
+                };
                 match result {
-                    InsertResult::Complete => {
+                    Err(NodeError::MissingBlock(next_id)) => {
+                        //the leaf split or migrated since the lookup, the key now lives on another peer
+                        let request = GeneralRequest::LeaseRequest(key, entry, next_id);
+                        forward_routed(client, &bp_tree, next_id, request)
+                            .await
+                            .unwrap_or_else(GeneralResponse::Error) //result of the insert in the right sibling
+                    }
+                    Err(err) => GeneralResponse::Error(err),
+                    Ok(InsertResult::Complete) => {
                         //if the insertion is successful
-                        println!("Success!");
+                        GeneralResponse::LeaseGranted(current_id)
+                    }
+                    Ok(InsertResult::Denied(holder, expires_at)) => {
+                        //another peer holds an unexpired lease for the key
+                        GeneralResponse::LeaseDenied { holder, expires_at }
                     }
                     //if it led to a split
-                    InsertResult::RightBlock(block_id, divider_key) => {
+                    Ok(InsertResult::RightBlock(block_id, divider_key)) => {
                         let id = block_id;
-                        let mut write_bp_tree = bp_tree.write().unwrap();
-                        let block = write_bp_tree.get_block(id).clone();
-                        let parent = block.parent();
-
-                        //if the parent is in the local block map
-                        if write_bp_tree.contains(parent) {
-                            write_bp_tree.insert_child(divider_key, block_id, parent);
-                            drop(write_bp_tree);
-                        }
-                        //else
-                        else {
-                            let divider_key_request = GeneralRequest::InsertOnRemoteParent(
-                                divider_key,
-                                block.parent(),
-                                id,
-                            );
-                            let peers = client.get_providers(block.parent().to_string()).await;
-                            let divider_key_requests = peers.into_iter().map(|p| {
-                                let mut network_client = client.clone();
-                                let divider_key_request = divider_key_request.clone();
-                                async move { network_client.request(p, divider_key_request).await }
-                                    .boxed()
-                            });
-
-                            let info = futures::future::select_ok(divider_key_requests).await;
-                            match info {
-                                Ok(str) => {
-                                }
-                                Err(err) => println!("Error {:?}", err),
-                            };
+                        let parent = match bp_tree.get_block(id) {
+                            Ok(block) => block.parent(),
+                            Err(err) => return GeneralResponse::Error(err),
+                        };
+                        //the right block is added to its parent before it leaves, wherever the parent lives now. it is
+                        //advertised first, so a split of the parent on another peer can tell it about the new one
+                        advertise_published(&bp_tree, client).await;
+                        let added = add_child(
+                            divider_key.clone(),
+                            parent,
+                            id,
+                            bp_tree.clone(),
+                            client,
+                            migrate_peer,
+                            migrations.clone(),
+                        );
+                        if let Err(err) = added.await {
+                            println!("Error {:?}", err);
                         }
-                        let migrate_request = GeneralRequest::MigrateRequest(block);
-                        let result = client.request(migrate_peer, migrate_request).await;
-                        let mut write_migrating_block = migrating_block.write().unwrap();
-                        write_migrating_block.insert(id);
-                        drop(write_migrating_block);
+                        advertise_new_root(top_id, &bp_tree, client).await;
                         println!("migrating");
-                        //request migration
-                        match result {
-                            Ok(_) => {
-                                println!("Completed migration");
-                                let mut write_bp_tree = bp_tree.write().unwrap();
-                                write_bp_tree.remove_block(id); //remove block from local b-plus tree
-                                drop(write_bp_tree);
-                                let mut write_migrating_block = migrating_block.write().unwrap();
-                                write_migrating_block.remove(&id); //remove id from record set
-                                drop(write_migrating_block);
-                                
-                                let q_records = queries.read().unwrap();
-                                if q_records.contains_key(&id) {
-                                    //if there are some queries that are needed to be flushed
-                                    let mut queries = queries.write().unwrap();
-                                    let pending_queries = queries.remove(&id).unwrap();
-                                    drop(queries);
-                                    for query in pending_queries {
-                                        let result = client.request(migrate_peer, query).await;
-                                        match result {
-                                            Ok(str) => {}
-                                            Err(err) => {
-                                                println!("Error {:?}", err);
-                                            }
-                                        }
-                                    }
-                                }
-                                drop(q_records);
-                            }
-                            Err(err) => {
-                                println!("Error {:?}", err);
-                            }
+                        migrate_block(id, client, migrate_peer, bp_tree.clone(), migrations).await;
+                        //the lease is granted even if the right block could not migrate
+                        if key >= divider_key {
+                            GeneralResponse::LeaseGranted(id)
+                        } else {
+                            GeneralResponse::LeaseGranted(current_id)
                         }
                     }
                 }
             }
-        }
-        //the current peer does not contain the id
-        else {
-           
-            let providers = client.get_providers(current_id.to_string()).await;
-            println!("{:?}", providers);
-            if providers.is_empty() {
-                println!("Could not find provider for lease.");
+            //the current peer does not contain the id
+            false => {
+                let lease = GeneralRequest::LeaseRequest(key, entry, current_id); //send a lease request to the next peer
+                forward_routed(client, &bp_tree, current_id, lease)
+                    .await
+                    .unwrap_or_else(GeneralResponse::Error) //propagate the result back to the caller
             }
-            let lease = GeneralRequest::LeaseRequest(key, entry,current_id); //send a lease request to the next peer
-            let requests = providers.into_iter().map(|p| {
-                let mut network_client = client.clone();
-                let lease = lease.clone();
-                async move { network_client.request(p, lease).await }.boxed()
-            });
-            let lease_info = futures::future::select_ok(requests).await;
-            match lease_info {
-                Ok(str) => {
-                    println!("Here {:?}", str.0);
+        }
+    };
+    println!("{:?}", bp_tree.get_block_map());
+    response
+}
+
+pub async fn handle_insert_on_remote_parent<K: TreeKey, V: TreeValue>(
+    key: K,
+    parent: BlockId,
+    child: BlockId,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    migrate_peer: PeerId,
+    migrations: Migrations<K, V>,
+) -> GeneralResponse<K, V> {
+    let top_id = bp_tree.get_top_id();
+    let response = match add_child(
+        key,
+        parent,
+        child,
+        bp_tree.clone(),
+        client,
+        migrate_peer,
+        migrations,
+    )
+    .await
+    {
+        Ok(holder) => GeneralResponse::ChildInserted(holder), //the child points to it from now on
+        Err(err) => GeneralResponse::Error(err),
+    };
+    advertise_new_root(top_id, &bp_tree, client).await;
+    println!("{:?}", bp_tree.get_block_map());
+    response
+}
+
+//a root split put a new block on top of the tree, the root record is refreshed so other peers find the peer holding it
+async fn advertise_new_root<K: TreeKey, V: TreeValue>(
+    top_id: BlockId,
+    bp_tree: &Arc<BPTree<K, V>>,
+    client: &mut Client,
+) {
+    advertise_published(bp_tree, client).await; //children that migrate point to the new root by its id
+    if bp_tree.get_top_id() != top_id && bp_tree.is_root() {
+        println!("New root {:?}", bp_tree.get_top_id());
+        client.boot_root().await;
+    }
+}
+
+//advertises the blocks split off on this peer that are still here, so a peer holding one of their
+//children or left siblings finds them by id
+pub async fn advertise_published<K: TreeKey, V: TreeValue>(
+    bp_tree: &Arc<BPTree<K, V>>,
+    client: &mut Client,
+) {
+    for id in bp_tree.take_published() {
+        if bp_tree.contains(id) {
+            client.start_providing(id.to_string()).await;
+        }
+    }
+}
+
+//block ids of two peers with the same prefix collide on every block, so each peer provides its prefix
+//and refuses to create blocks under a prefix another peer already provides
+pub async fn claim_id_prefix<K: TreeKey, V: TreeValue>(
+    bp_tree: &Arc<BPTree<K, V>>,
+    client: &mut Client,
+    me: PeerId,
+) -> Result<(), NodeError> {
+    let key = format!("ids-{:08x}", bp_tree.id_prefix());
+    if let Ok(providers) = client.get_providers(key.clone()).await {
+        if let Some(peer) = providers.into_iter().find(|peer| *peer != me) {
+            return Err(NodeError::PrefixTaken(peer));
+        }
+    }
+    client.start_providing(key).await;
+    Ok(())
+}
+
+const SPLIT_RETRIES: usize = 5;
+const SPLIT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+//adds a child to the block that covers its divider key, starting from the parent it points to, and returns that block.
+//a parent that splits in turn hands its right block to its own parent, level by level, and the right blocks
+//migrate once they are linked. children held by other peers are told about their new parent
+async fn add_child<K: TreeKey, V: TreeValue>(
+    key: K,
+    parent: BlockId,
+    child: BlockId,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    migrate_peer: PeerId,
+    migrations: Migrations<K, V>,
+) -> Result<BlockId, NodeError> {
+    let (mut key, mut parent, mut child) = (key, parent, child);
+    let mut holder = None; //block that took the first child
+    let mut split_blocks = Vec::new();
+    let mut retries = 0;
+    let result = loop {
+        let child_key = key.clone();
+        let taken = match bp_tree.move_right(parent, &key) {
+            //the parent may have split since the child pointed to it
+            Ok(target) => match blocking(&bp_tree, move |tree| {
+                tree.insert_child(child_key, child, target)
+            })
+            .await
+            {
+                Ok((left_id, InsertResult::RightBlock(right_id, divider_key))) => {
+                    let taken = if key >= divider_key {
+                        right_id
+                    } else {
+                        left_id
+                    };
+                    holder.get_or_insert(taken);
+                    advertise_published(&bp_tree, client).await; //before a split of the grandparent tells the right block
+                    set_unlinked_parents(&bp_tree, client, &migrations).await;
+                    split_blocks.push(right_id);
+                    match bp_tree.get_block(right_id).map(|right| right.parent()) {
+                        Ok(0) => break Ok(()), //nothing above it, only left by root splits of older versions
+                        Ok(grandparent) => {
+                            key = divider_key;
+                            parent = grandparent;
+                            child = right_id;
+                            continue;
+                        }
+                        Err(err) => break Err(err),
+                    }
                 }
-                Err(err) => {
-                    println!("Error {:?}", err);
+                Ok((taken, _)) => Ok(taken),
+                //the block migrated since move_right, the next lookup finds it on its new peer
+                Err(NodeError::MissingBlock(_)) if retries < SPLIT_RETRIES => {
+                    retries += 1;
+                    continue;
                 }
-            };
+                Err(NodeError::BlockMigrating(id)) => {
+                    //requests queued for the parent follow it to its new peer
+                    let request = GeneralRequest::InsertOnRemoteParent(key.clone(), id, child);
+                    match migrations.queue(id, request) {
+                        Some(receiver) => match receiver.await {
+                            Ok(response) => child_inserted(response),
+                            Err(_) => Err(NodeError::BlockMigrating(id)),
+                        },
+                        None => Err(NodeError::BlockMigrating(id)),
+                    }
+                }
+                Err(err) => Err(err),
+            },
+            Err(remote) => {
+                //the parent, or the right sibling that took over the key, is held by another peer
+                let request: GeneralRequest<K, V> =
+                    GeneralRequest::InsertOnRemoteParent(key.clone(), remote, child);
+                match forward_to_providers(client, remote, request).await {
+                    Ok(response) => child_inserted(response),
+                    Err(err) => Err(err),
+                }
+            }
+        };
+        match taken {
+            Ok(taken) => {
+                //a child here that still points to the old parent was not updated by insert_child, the insert went to
+                //another peer or the child is being sent. a child held by another peer is updated by the peer that asked
+                let stale = bp_tree
+                    .get_block(child)
+                    .map_or(false, |block| taken != parent && block.parent() == parent);
+                if stale {
+                    if let Err(err) =
+                        set_parent(child, parent, taken, &bp_tree, client, &migrations).await
+                    {
+                        println!("Error {:?}", err);
+                    }
+                }
+                holder.get_or_insert(taken);
+                break Ok(());
+            }
+            Err(NodeError::BlockMigrating(_)) if retries < SPLIT_RETRIES => {
+                //the parent is in a handoff that was not decided yet
+                retries += 1;
+                tokio::time::sleep(SPLIT_RETRY_DELAY).await;
+            }
+            Err(err) => break Err(err),
+        }
+    };
+    for right_id in split_blocks {
+        migrate_block(
+            right_id,
+            client,
+            migrate_peer,
+            bp_tree.clone(),
+            migrations.clone(),
+        )
+        .await;
+    }
+    match (result, holder) {
+        //the child is in, only a split above it failed. the caller still has to point the child to its holder
+        (Err(err), Some(holder)) => {
+            println!("Error {:?}", err);
+            Ok(holder)
+        }
+        (result, holder) => {
+            result?;
+            holder.ok_or(NodeError::MissingBlock(parent))
         }
     }
-    let bp_tree = bp_tree.read().unwrap();
-    println!("{:?}", bp_tree.get_block_map());
 }
 
-pub async fn handle_insert_on_remote_parent(
-    key: Key,
+fn child_inserted<K: TreeKey, V: TreeValue>(
+    response: GeneralResponse<K, V>,
+) -> Result<BlockId, NodeError> {
+    match response.result() {
+        GeneralResponse::ChildInserted(taken) => Ok(taken),
+        GeneralResponse::Error(err) => Err(err),
+        response => Err(unexpected(response)),
+    }
+}
+
+//children a split moved to the right block while they were on other peers or being sent learn about it. a child whose
+//move to the block that split is still on its way, that another task has yet to advertise, or whose provider records
+//lag a handoff is retried in the background, as that move may wait for the caller of this very task
+async fn set_unlinked_parents<K: TreeKey, V: TreeValue>(
+    bp_tree: &Arc<BPTree<K, V>>,
+    client: &mut Client,
+    migrations: &Migrations<K, V>,
+) {
+    for (child, left_id, right_id) in bp_tree.take_unlinked() {
+        match set_parent(child, left_id, right_id, bp_tree, client, migrations).await {
+            Err(err) if parent_pending(&err) => {
+                let (bp_tree, mut client, migrations) =
+                    (bp_tree.clone(), client.clone(), migrations.clone());
+                tokio::spawn(async move {
+                    let mut retries = 0;
+                    loop {
+                        tokio::time::sleep(SPLIT_RETRY_DELAY).await;
+                        match set_parent(
+                            child,
+                            left_id,
+                            right_id,
+                            &bp_tree,
+                            &mut client,
+                            &migrations,
+                        )
+                        .await
+                        {
+                            Err(err) if parent_pending(&err) && retries < SPLIT_RETRIES => {
+                                retries += 1
+                            }
+                            Err(err) => {
+                                println!("Error {:?}", err);
+                                break;
+                            }
+                            Ok(()) => break,
+                        }
+                    }
+                });
+            }
+            Err(err) => println!("Error {:?}", err),
+            Ok(()) => {}
+        }
+    }
+}
+
+//errors of a parent update that may still succeed once the requests it waits for went through
+fn parent_pending(err: &NodeError) -> bool {
+    matches!(
+        err,
+        NodeError::ParentMoved(..) | NodeError::NoProvider(_) | NodeError::MissingBlock(_)
+    )
+}
+
+async fn set_parent<K: TreeKey, V: TreeValue>(
+    child: BlockId,
+    expected: BlockId,
     parent: BlockId,
+    bp_tree: &Arc<BPTree<K, V>>,
+    client: &mut Client,
+    migrations: &Migrations<K, V>,
+) -> Result<(), NodeError> {
+    if bp_tree.contains(child) {
+        //a child being handed off learns its parent on the peer it moves to
+        let response =
+            handle_set_parent(child, expected, parent, bp_tree.clone(), migrations.clone());
+        match response.await.result() {
+            GeneralResponse::ParentSet(_) => return Ok(()),
+            //a stale parent is left of the actual one, moving right from it still finds the block
+            GeneralResponse::Error(NodeError::BlockMigrating(_)) => return Ok(()),
+            GeneralResponse::Error(NodeError::MissingBlock(_)) => {} //migrated meanwhile, it learns it on its new peer
+            GeneralResponse::Error(err) => return Err(err),
+            response => return Err(unexpected(response)),
+        }
+    }
+    let request: GeneralRequest<K, V> = GeneralRequest::SetParent(child, expected, parent);
+    match forward_to_providers(client, child, request).await?.result() {
+        GeneralResponse::ParentSet(_) => Ok(()),
+        GeneralResponse::Error(err) => Err(err),
+        response => Err(unexpected(response)),
+    }
+}
+
+pub async fn handle_set_parent<K: TreeKey, V: TreeValue>(
     child: BlockId,
-    bp_tree: Arc<RwLock<BPTree>>,
+    expected: BlockId,
+    parent: BlockId,
+    bp_tree: Arc<BPTree<K, V>>,
+    migrations: Migrations<K, V>,
+) -> GeneralResponse<K, V> {
+    loop {
+        if let Some(receiver) =
+            migrations.queue(child, GeneralRequest::SetParent(child, expected, parent))
+        {
+            return receiver
+                .await
+                .unwrap_or(GeneralResponse::Error(NodeError::BlockMigrating(child)));
+        }
+        match blocking(&bp_tree, move |tree| {
+            tree.set_parent(child, expected, parent)
+        })
+        .await
+        {
+            Ok(()) => return GeneralResponse::ParentSet(child),
+            //frozen after the queue was checked, the request is queued now
+            Err(NodeError::BlockMigrating(_)) if migrations.blocks().contains(&child) => continue,
+            Err(err) => return GeneralResponse::Error(err),
+        }
+    }
+}
+
+//sends a block to the migrate peer and flushes the requests queued while it was migrating
+async fn migrate_block<K: TreeKey, V: TreeValue>(
+    id: BlockId,
     client: &mut Client,
     migrate_peer: PeerId,
-    migrating_block: Arc<RwLock<HashSet<BlockId>>>,
-    queries: Arc<RwLock<HashMap<BlockId, Vec<GeneralRequest>>>>,
+    bp_tree: Arc<BPTree<K, V>>,
+    migrations: Migrations<K, V>,
 ) {
-    let parent_block = {
-        let bp_tree = bp_tree.read().unwrap();
-        bp_tree.get_block(parent)
+    if migrate_peer == client.local_peer_id() {
+        return; //no other peer is known yet, the block stays here
+    }
+    if !migrations.begin(id) {
+        return; //already on its way to another peer
+    }
+    //copied once the block is marked, requests arriving from now on wait for the new provider
+    let migrated = match blocking(&bp_tree, move |tree| tree.freeze(id, migrate_peer)).await {
+        Ok(block) => hand_off(block, client, migrate_peer, &bp_tree).await,
+        Err(err) => {
+            println!("Error {:?}", err);
+            false
+        }
     };
-    if key >= parent_block.return_divider_key() {
-        //if the key does not belong to this parent block anymore
-        let right_block = parent_block.return_next_block(); //adjacent block of the internal block
-        let peer = client.get_providers(right_block.to_string()).await; //get the right block
-        let next_call = GeneralRequest::InsertOnRemoteParent(key, right_block, child);
-        let requests = peer.into_iter().map(|p| {
-            let mut network_client = client.clone();
-            let next_call = next_call.clone();
-            async move { network_client.request(p, next_call).await }.boxed()
-        });
-        let insert_info = futures::future::select_ok(requests).await;
-        match insert_info {
-            Ok(str) => {
-                let response: GeneralResponse = serde_json::from_str(&str.0).unwrap();
-                match response {
-                    GeneralResponse::InsertOnRemoteParent => {}
-                    GeneralResponse::MigrateResponse => {}
-                    GeneralResponse::LeaseResponse => {}
-                }
-            }
+    flush_queued(id, migrated, client, migrate_peer, bp_tree, migrations).await;
+}
+
+//handoffs this peer started before it stopped are decided before it serves or gives up their blocks: the receiver
+//is asked to abort, and a block it had already taken over is dropped here
+pub async fn resume_handoffs<K: TreeKey, V: TreeValue>(
+    client: &mut Client,
+    bp_tree: Arc<BPTree<K, V>>,
+    migrations: Migrations<K, V>,
+) {
+    for (id, migrate_peer) in bp_tree.sending() {
+        let meta = match bp_tree.get_block(id) {
+            Ok(block) => block.meta(),
             Err(err) => {
                 println!("Error {:?}", err);
+                continue;
             }
         };
-    } else {
-        let mut bp_tree = bp_tree.write().unwrap();
-        let result = bp_tree.insert_child(key, child, parent);
-        match result {
-            //Successful insertion
-            InsertResult::Complete => {}
-            //Insertion led to split
-            InsertResult::RightBlock(right_block_id, divider_key) => {
-                //right block to split
-                let block = bp_tree.get_block(right_block_id).clone();
-                let migrate_request = GeneralRequest::MigrateRequest(block);
-                let mut migrating_block = migrating_block.write().unwrap();
-                migrating_block.insert(right_block_id);
-                let result = client.request(migrate_peer, migrate_request).await; //request migration
-                match result {
-                    Ok(_) => {
-                        bp_tree.remove_block(right_block_id); //remove block from local b-plus tree
-                        drop(bp_tree);
-                        migrating_block.remove(&right_block_id); //remove id from record set
-                        let q_records = queries.read().unwrap();
-                        if q_records.contains_key(&right_block_id) {
-                            let mut queries = queries.write().unwrap();
-                            let pending_queries = queries.remove(&right_block_id).unwrap();
-                            drop(queries);
-                            for query in pending_queries {
-                                let result = client.request(migrate_peer, query).await;
-                                match result {
-                                    Ok(str) => {}
-                                    Err(err) => {
-                                        println!("Error {:?}", err);
-                                    }
-                                }
-                            }
-                        }
-                        drop(q_records);
-                    }
-                    Err(err) => {
-                        println!("Error {:?}", err);
-                    }
+        if !migrations.begin(id) {
+            continue;
+        }
+        let is_top = bp_tree.get_top_id() == id;
+        let committed = if migrate_peer == client.local_peer_id() {
+            Ok(false) //a handoff to this peer itself never reached anyone
+        } else {
+            abort_migration(id, client, migrate_peer).await
+        };
+        let migrated = match committed {
+            Ok(committed) => end_handoff(id, meta, committed, client, &bp_tree).await,
+            Err(err) => {
+                println!(
+                    "Error {:?}, block {:?} stays frozen until its handoff is decided",
+                    err, id
+                );
+                flush_queued(
+                    id,
+                    false,
+                    client,
+                    migrate_peer,
+                    bp_tree.clone(),
+                    migrations.clone(),
+                )
+                .await;
+                continue;
+            }
+        };
+        if !migrated {
+            //the records were not advertised on startup
+            client.start_providing(id.to_string()).await;
+            if is_top {
+                client.boot_root().await;
+                client.subscribe(Topic::new(SIZE_TOPIC)).await;
+            }
+        }
+        flush_queued(
+            id,
+            migrated,
+            client,
+            migrate_peer,
+            bp_tree.clone(),
+            migrations.clone(),
+        )
+        .await;
+    }
+}
+
+//the block is no longer marked and the requests queued for it are flushed
+async fn flush_queued<K: TreeKey, V: TreeValue>(
+    id: BlockId,
+    migrated: bool,
+    client: &mut Client,
+    migrate_peer: PeerId,
+    bp_tree: Arc<BPTree<K, V>>,
+    migrations: Migrations<K, V>,
+) {
+    //sent side by side, a queued request that splits or waits on other handoffs does not hold up the rest
+    let queued = migrations.finish(id).into_iter().map(|(query, sender)| {
+        let mut client = client.clone();
+        let (bp_tree, migrations) = (bp_tree.clone(), migrations.clone());
+        async move {
+            let response = if migrated {
+                send_request(&mut client, migrate_peer, query).await
+            } else {
+                run_local(query, &mut client, migrate_peer, bp_tree, migrations).await
+                //the block is thawed
+            };
+            let _ = sender.send(response);
+        }
+    });
+    future::join_all(queued).await;
+}
+
+//handles a request queued for a block whose handoff was aborted on this peer again. boxed, as the handlers
+//migrate the blocks they split in turn
+fn run_local<'a, K: TreeKey, V: TreeValue>(
+    query: GeneralRequest<K, V>,
+    client: &'a mut Client,
+    migrate_peer: PeerId,
+    bp_tree: Arc<BPTree<K, V>>,
+    migrations: Migrations<K, V>,
+) -> future::BoxFuture<'a, GeneralResponse<K, V>> {
+    async move {
+        match query {
+            GeneralRequest::LeaseRequest(key, entry, block_id) => {
+                handle_lease_request(
+                    key,
+                    entry,
+                    bp_tree,
+                    client,
+                    migrate_peer,
+                    migrations,
+                    block_id,
+                )
+                .await
+            }
+            GeneralRequest::InsertOnRemoteParent(key, parent, child) => {
+                handle_insert_on_remote_parent(
+                    key,
+                    parent,
+                    child,
+                    bp_tree,
+                    client,
+                    migrate_peer,
+                    migrations,
+                )
+                .await
+            }
+            GeneralRequest::SetParent(child, expected, parent) => {
+                handle_set_parent(child, expected, parent, bp_tree, migrations).await
+            }
+            query => GeneralResponse::Error(unexpected_request(query)),
+        }
+    }
+    .boxed()
+}
+
+//moves a block to the peer, leaf or internal, and with subtree every block below it held by this peer. the lowest
+//levels move first, so a parent leaves only after its children. returns the blocks that moved
+pub async fn handle_migrate_blocks<K: TreeKey, V: TreeValue>(
+    id: BlockId,
+    subtree: bool,
+    client: &mut Client,
+    peer: PeerId,
+    bp_tree: Arc<BPTree<K, V>>,
+    migrations: Migrations<K, V>,
+) -> Result<Vec<BlockId>, NodeError> {
+    if !bp_tree.contains(id) {
+        return Err(NodeError::MissingBlock(id));
+    }
+    let mut ids = vec![id];
+    let mut next = 0;
+    while subtree && next < ids.len() {
+        let children = bp_tree
+            .get_block(ids[next])
+            .map(|block| block.children())
+            .unwrap_or_default();
+        ids.extend(
+            children
+                .into_iter()
+                .filter(|child| bp_tree.contains(*child)),
+        ); //children on other peers stay
+        next += 1;
+    }
+    let mut moved = Vec::new();
+    for id in ids.into_iter().rev() {
+        //children staying here may never have been advertised, the peer reaches them by id from the parent
+        let children = bp_tree
+            .get_block(id)
+            .map(|block| block.children())
+            .unwrap_or_default();
+        for child in children
+            .into_iter()
+            .filter(|child| bp_tree.contains(*child))
+        {
+            client.start_providing(child.to_string()).await;
+        }
+        migrate_block(id, client, peer, bp_tree.clone(), migrations.clone()).await;
+        if !bp_tree.contains(id) {
+            moved.push(id);
+        }
+    }
+    Ok(moved)
+}
+
+//moves a frozen block to the peer in two phases: the peer stores and advertises a frozen copy (prepare),
+//then takes it over (commit) and only then this peer drops its own. the block has exactly one owner
+//at any time, and if the peer fails before the commit the handoff is aborted and the block stays here
+async fn hand_off<K: TreeKey, V: TreeValue>(
+    block: Block<K, V>,
+    client: &mut Client,
+    migrate_peer: PeerId,
+    bp_tree: &Arc<BPTree<K, V>>,
+) -> bool {
+    let id = block.return_id();
+    let meta = block.meta();
+    let is_top = bp_tree.get_top_id() == id;
+    let committed = match send_request(client, migrate_peer, GeneralRequest::MigrateRequest(block))
+        .await
+        .result()
+    {
+        GeneralResponse::Prepared(_) => {
+            let commit: GeneralRequest<K, V> = GeneralRequest::CommitMigration(id, is_top);
+            match send_request(client, migrate_peer, commit).await.result() {
+                GeneralResponse::Migrated(_) => Ok(true),
+                response => {
+                    println!("Error {:?}", response);
+                    abort_migration(id, client, migrate_peer).await
                 }
             }
         }
+        response => {
+            println!("Error {:?}", response);
+            abort_migration(id, client, migrate_peer).await //the peer may have stored the block before failing
+        }
+    };
+    match committed {
+        Ok(committed) => end_handoff(id, meta, committed, client, bp_tree).await,
+        Err(err) => {
+            //the handoff stays logged, a restart asks the peer again
+            println!(
+                "Error {:?}, block {:?} stays frozen until its handoff is decided",
+                err, id
+            );
+            false
+        }
     }
-    let bp_tree = bp_tree.read().unwrap();
-    println!("{:?}", bp_tree.get_block_map());
 }
 
-pub async fn handle_migrate(block: Block, bp_tree: Arc<RwLock<BPTree>>, client: &mut Client) {
+//drops the block once the peer took it over, or keeps serving it if the peer dropped its copy
+async fn end_handoff<K: TreeKey, V: TreeValue>(
+    id: BlockId,
+    meta: BlockMeta<K>,
+    committed: bool,
+    client: &mut Client,
+    bp_tree: &Arc<BPTree<K, V>>,
+) -> bool {
+    if !committed {
+        if let Err(err) = blocking(bp_tree, move |tree| tree.thaw(id)).await {
+            println!("Error {:?}", err);
+        }
+        return false;
+    }
+    println!("Completed migration");
+    let is_top = bp_tree.get_top_id() == id;
+    if let Err(err) = blocking(bp_tree, move |tree| tree.remove_block(id)).await {
+        //remove block from local b-plus tree
+        println!("Error {:?}", err);
+    }
+    client.stop_providing(id.to_string()).await;
+    if is_top {
+        client.stop_providing("root".to_string()).await; //the receiver advertises the root now
+    }
+    bp_tree.learn_route(meta); //requests for the block are now routed with the generation it left with
+    true
+}
+
+const HANDOFF_RETRIES: usize = 10;
+const HANDOFF_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+//false once the peer dropped its copy, true if it had already taken the block over. the block must not be owned
+//twice, so it stays frozen if the peer does not answer within the retries
+async fn abort_migration(
+    id: BlockId,
+    client: &mut Client,
+    migrate_peer: PeerId,
+) -> Result<bool, NodeError> {
+    let mut retries = 0;
+    loop {
+        let request: GeneralRequest = GeneralRequest::AbortMigration(id);
+        let response: GeneralResponse = send_request(client, migrate_peer, request).await.result();
+        match response {
+            GeneralResponse::Aborted(_) => return Ok(false),
+            GeneralResponse::Migrated(_) => return Ok(true),
+            GeneralResponse::Error(err) if retries == HANDOFF_RETRIES => return Err(err),
+            response if retries == HANDOFF_RETRIES => return Err(unexpected(response)),
+            response => {
+                println!("Error {:?}, retrying the abort of block {:?}", response, id);
+                retries += 1;
+                tokio::time::sleep(HANDOFF_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+//prepare: stores the block frozen and advertises it, writes wait for the commit of the sender
+pub async fn handle_migrate<K: TreeKey, V: TreeValue>(
+    block: Block<K, V>,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+) -> GeneralResponse<K, V> {
     let child_id = block.return_id();
-    let mut write_bp_tree = bp_tree.write().unwrap();
-    write_bp_tree.add_block(child_id, block);
-    drop(write_bp_tree);
+    let prepared = blocking(&bp_tree, move |tree| {
+        tree.prepare_block(child_id, block).map_err(|err| {
+            let _ = tree.remove_block(child_id); //the sender keeps the block
+            err
+        })
+    });
+    if let Err(err) = prepared.await {
+        return GeneralResponse::Error(err);
+    }
     client.start_providing(child_id.to_string()).await;
-    let bp_tree = bp_tree.read().unwrap();
+    GeneralResponse::Prepared(child_id) //the block is stored and advertised
+}
+
+//commit: the sender drops its copy once this answers. the receiver of the top block becomes the root peer
+pub async fn handle_commit_migration<K: TreeKey, V: TreeValue>(
+    id: BlockId,
+    is_top: bool,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+) -> GeneralResponse<K, V> {
+    if !bp_tree.contains(id) {
+        return GeneralResponse::Error(NodeError::MissingBlock(id));
+    }
+    if is_top && bp_tree.is_frozen(id) {
+        if let Err(err) = blocking(&bp_tree, move |tree| tree.set_top_id(id)).await {
+            return GeneralResponse::Error(err);
+        }
+        client.boot_root().await;
+        client.subscribe(Topic::new(SIZE_TOPIC)).await; //tree sizes are gossiped to the root peer
+    }
+    if let Err(err) = blocking(&bp_tree, move |tree| tree.thaw(id)).await {
+        return GeneralResponse::Error(err);
+    }
     println!("{:?}", bp_tree.get_block_map());
+    GeneralResponse::Migrated(id)
+}
+
+//abort: drops a block that is still frozen, a block already committed stays
+pub async fn handle_abort_migration<K: TreeKey, V: TreeValue>(
+    id: BlockId,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+) -> GeneralResponse<K, V> {
+    if !bp_tree.contains(id) {
+        return GeneralResponse::Aborted(id); //the prepare never arrived
+    }
+    if !bp_tree.is_frozen(id) {
+        return GeneralResponse::Migrated(id);
+    }
+    if let Err(err) = blocking(&bp_tree, move |tree| tree.remove_block(id)).await {
+        return GeneralResponse::Error(err);
+    }
+    client.stop_providing(id.to_string()).await;
+    GeneralResponse::Aborted(id)
+}
+
+//loads the sorted entries into the local tree and ships every block but the top to the peers in turn
+pub async fn handle_bulk_load<K: TreeKey, V: TreeValue>(
+    entries: Vec<(K, Entry<K, V>)>,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    peers: Vec<PeerId>,
+    migrations: Migrations<K, V>,
+) -> Result<usize, NodeError> {
+    let top_id = blocking(&bp_tree, move |tree| tree.bulk_load(entries)).await?;
+    client.start_providing(top_id.to_string()).await; //the children that migrate point to it by id
+    let ids: Vec<BlockId> = bp_tree
+        .block_ids()
+        .into_iter()
+        .filter(|id| *id != top_id)
+        .collect();
+    let mut migrated = 0;
+    for (i, id) in ids.into_iter().enumerate() {
+        if !peers.is_empty() {
+            let peer = peers[i % peers.len()];
+            migrate_block(id, client, peer, bp_tree.clone(), migrations.clone()).await;
+        }
+        if bp_tree.contains(id) {
+            client.start_providing(id.to_string()).await; //the block stays on this peer
+        } else {
+            migrated += 1;
+        }
+    }
+    println!("{:?}", bp_tree.get_block_map());
+    Ok(migrated)
+}
+
+pub async fn handle_get_lease<K: TreeKey, V: TreeValue>(
+    key: K,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    block_id: BlockId,
+) -> Result<Option<Entry<K, V>>, NodeError> {
+    let next_id = match bp_tree.locate(block_id, &key) {
+        //read operation
+        Ok(leaf_id) => return bp_tree.entry_at(leaf_id, &key),
+        Err(next_id) => next_id, //the block is held by another peer
+    };
+    let request = GeneralRequest::GetLease(key, next_id);
+    match forward_routed(client, &bp_tree, next_id, request)
+        .await?
+        .result()
+    {
+        GeneralResponse::LeaseFound(entry) => Ok(entry),
+        GeneralResponse::Error(err) => Err(err),
+        response => Err(unexpected(response)),
+    }
+}
+
+pub async fn handle_renew_lease<K: TreeKey, V: TreeValue>(
+    key: K,
+    holder: PeerId,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    block_id: BlockId,
+) -> Result<bool, NodeError> {
+    let next_id = match bp_tree.locate(block_id, &key) {
+        Ok(leaf_id) => {
+            return blocking(&bp_tree, move |tree| {
+                tree.renew_lease(leaf_id, &key, holder)
+            })
+            .await
+        } //write operation
+        Err(next_id) => next_id,
+    };
+    let request: GeneralRequest<K, V> = GeneralRequest::RenewLease(key, holder, next_id);
+    match forward_routed(client, &bp_tree, next_id, request)
+        .await?
+        .result()
+    {
+        GeneralResponse::LeaseRenewed(renewed) => Ok(renewed),
+        GeneralResponse::Error(err) => Err(err),
+        response => Err(unexpected(response)),
+    }
+}
+
+pub async fn handle_release_lease<K: TreeKey, V: TreeValue>(
+    key: K,
+    holder: PeerId,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    block_id: BlockId,
+) -> Result<bool, NodeError> {
+    let next_id = match bp_tree.locate(block_id, &key) {
+        Ok(leaf_id) => {
+            return blocking(&bp_tree, move |tree| {
+                tree.release_lease(leaf_id, &key, holder)
+            })
+            .await
+        } //write operation
+        Err(next_id) => next_id,
+    };
+    let request: GeneralRequest<K, V> = GeneralRequest::ReleaseLease(key, holder, next_id);
+    match forward_routed(client, &bp_tree, next_id, request)
+        .await?
+        .result()
+    {
+        GeneralResponse::LeaseReleased(released) => Ok(released),
+        GeneralResponse::Error(err) => Err(err),
+        response => Err(unexpected(response)),
+    }
+}
+
+pub async fn handle_compare_and_swap<K: TreeKey, V: TreeValue>(
+    key: K,
+    expected_version: u64,
+    new_data: V,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    block_id: BlockId,
+) -> Result<CasResult, NodeError> {
+    let next_id = match bp_tree.locate(block_id, &key) {
+        Ok(leaf_id) => {
+            //write operation
+            return blocking(&bp_tree, move |tree| {
+                tree.compare_and_swap(leaf_id, &key, expected_version, new_data)
+            })
+            .await;
+        }
+        Err(next_id) => next_id,
+    };
+    let request = GeneralRequest::CompareAndSwap {
+        key,
+        expected_version,
+        new_data,
+        block_id: next_id,
+    };
+    match forward_routed(client, &bp_tree, next_id, request)
+        .await?
+        .result()
+    {
+        GeneralResponse::CompareAndSwapped(result) => Ok(result),
+        GeneralResponse::Error(err) => Err(err),
+        response => Err(unexpected(response)),
+    }
+}
+
+//collects every block reachable from the top across the providers and checks them as one block map
+pub async fn check_tree<K: TreeKey, V: TreeValue>(
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    block_id: BlockId,
+) -> Result<Vec<Violation>, NodeError> {
+    let mut blocks = HashMap::new();
+    let mut unreachable = Vec::new();
+    let mut top_id = block_id;
+    let mut pending = vec![block_id];
+    while let Some(id) = pending.pop() {
+        if blocks.contains_key(&id) || unreachable.contains(&id) {
+            continue;
+        }
+        let local = bp_tree.get_block(id).ok();
+        let block = match local {
+            Some(block) => block,
+            None => {
+                let request: GeneralRequest<K, V> = GeneralRequest::GetBlock(id);
+                match forward_to_providers(client, id, request)
+                    .await
+                    .map(|response| response.result())
+                {
+                    Ok(GeneralResponse::BlockFound(block)) => block,
+                    Err(err) if id == block_id => return Err(err), //the walk cannot start
+                    _ => {
+                        unreachable.push(id);
+                        continue;
+                    }
+                }
+            }
+        };
+        if id == block_id {
+            top_id = block.return_id(); //the default id resolves to the top block of the root provider
+        }
+        pending.extend(block.children());
+        if block.return_next_block() != 0 {
+            pending.push(block.return_next_block());
+        }
+        blocks.insert(block.return_id(), block);
+    }
+    let mut violations = check_blocks(&blocks, top_id);
+    violations.extend(unreachable.into_iter().map(Violation::Unreachable));
+    Ok(violations)
+}
+
+pub async fn handle_expire_leases<K: TreeKey, V: TreeValue>(
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    migrations: Migrations<K, V>,
+) {
+    let migrating = migrations.blocks();
+    let (expired, checkpoint) = blocking(&bp_tree, move |tree| {
+        //the sweep doubles as the periodic checkpoint of the write-ahead log
+        (tree.expire_leases(&migrating), tree.checkpoint())
+    })
+    .await;
+    match expired {
+        Ok(0) => {}
+        Ok(expired) => println!("Expired {:?} leases", expired),
+        Err(err) => println!("Error {:?}", err),
+    }
+    if let Err(err) = checkpoint {
+        println!("Error {:?}", err);
+    }
+    advertise_published(&bp_tree, client).await; //blocks split off by a merge
+}
+
+pub async fn handle_range_scan<K: TreeKey, V: TreeValue>(
+    start: K,
+    end: K,
+    bp_tree: Arc<BPTree<K, V>>,
+    cursor: BlockId,
+) -> (Vec<(K, Entry<K, V>)>, Option<BlockId>) {
+    blocking(&bp_tree, move |tree| {
+        let mut range = tree.range_from(cursor, start, end); //read operation, waits for the latches of writers
+        let entries: Vec<(K, Entry<K, V>)> = range.by_ref().collect();
+        (entries, range.cursor()) //cursor is set when the range continues on another peer
+    })
+    .await
+}
+
+pub async fn range_scan<K: TreeKey, V: TreeValue>(
+    start: K,
+    end: K,
+    bp_tree: Arc<BPTree<K, V>>,
+    client: &mut Client,
+    block_id: BlockId,
+) -> Result<Vec<(K, Entry<K, V>)>, NodeError> {
+    let local = bp_tree.contains(block_id);
+    let (mut entries, mut cursor) = if local {
+        handle_range_scan(start.clone(), end.clone(), bp_tree, block_id).await
+    } else {
+        (Vec::new(), Some(block_id))
+    };
+    let mut retries = 0;
+    while let Some(next_id) = cursor {
+        //continue the scan on the provider of the block the range moved to
+        let request = GeneralRequest::RangeScan {
+            start: start.clone(),
+            end: end.clone(),
+            cursor: next_id,
+        };
+        match forward_to_providers(client, next_id, request)
+            .await?
+            .result()
+        {
+            GeneralResponse::RangeResult {
+                cursor: next_cursor,
+                ..
+            } if next_cursor == Some(next_id) => {
+                //the block left the provider between its check and the scan, its new provider is asked
+                if retries == SPLIT_RETRIES {
+                    return Err(NodeError::MissingBlock(next_id));
+                }
+                retries += 1;
+                tokio::time::sleep(SPLIT_RETRY_DELAY).await;
+            }
+            GeneralResponse::RangeResult {
+                entries: chunk,
+                cursor: next_cursor,
+            } => {
+                println!(
+                    "Received {:?} entries from block {:?}",
+                    chunk.len(),
+                    next_id
+                );
+                entries.extend(chunk);
+                cursor = next_cursor;
+                retries = 0;
+            }
+            GeneralResponse::Error(err) => return Err(err),
+            response => return Err(unexpected(response)),
+        }
+    }
+    Ok(entries)
+}
+
+async fn forward_to_providers<K: TreeKey, V: TreeValue>(
+    client: &mut Client,
+    block_id: BlockId,
+    request: GeneralRequest<K, V>,
+) -> Result<GeneralResponse<K, V>, NodeError> {
+    //the default block id stands for the root of the tree
+    let provider = if block_id == 0 {
+        "root".to_string()
+    } else {
+        block_id.to_string()
+    };
+    let mut retries = 0;
+    loop {
+        let providers = client.get_providers(provider.clone()).await?;
+        let migrating = AtomicBool::new(false);
+        let requests = providers.into_iter().map(|p| {
+            let mut network_client = client.clone();
+            let request = request.clone();
+            let migrating = &migrating;
+            async move {
+                let str = network_client.request(p, request).await?;
+                let response = parse_response(p, &str)?;
+                //provider records outlive a block on the peers it migrated away from, the next provider is tried
+                match &response {
+                    GeneralResponse::Forwarded(_, inner) if inner.is_missing(block_id) => {
+                        Err(NodeError::MissingBlock(block_id))
+                    }
+                    //a copy prepared on the peer a block moves to refuses changes until the commit, the sender queues them
+                    GeneralResponse::Forwarded(_, inner) if inner.is_migrating(block_id) => {
+                        migrating.store(true, Ordering::SeqCst);
+                        Err(NodeError::BlockMigrating(block_id))
+                    }
+                    _ => Ok(response),
+                }
+            }
+            .boxed()
+        });
+        let answered = futures::future::select_ok(requests).await;
+        match answered {
+            Ok((response, _)) => return Ok(response),
+            //the sender dropped its copy before the receiver got the commit, the receiver answers once it did
+            Err(_) if migrating.load(Ordering::SeqCst) && retries < SPLIT_RETRIES => {
+                retries += 1;
+                tokio::time::sleep(SPLIT_RETRY_DELAY).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+const MAX_REDIRECTS: usize = 4;
+
+//forwards a request routed by key along with the generation this peer last saw for the target block,
+//a peer holding a newer generation answers with the block metadata and the request is sent to the next hop it gives
+async fn forward_routed<K: TreeKey, V: TreeValue>(
+    client: &mut Client,
+    bp_tree: &Arc<BPTree<K, V>>,
+    block_id: BlockId,
+    mut request: GeneralRequest<K, V>,
+) -> Result<GeneralResponse<K, V>, NodeError> {
+    let mut block_id = block_id;
+    for _ in 0..MAX_REDIRECTS {
+        let generation = bp_tree.expected_generation(block_id);
+        let routed = match generation {
+            Some(generation) => GeneralRequest::Routed {
+                generation,
+                request: Box::new(request.clone()),
+            },
+            None => request.clone(), //nothing known about the block, the receiver routes on its own
+        };
+        let response = forward_to_providers(client, block_id, routed).await?;
+        let meta = match response.redirect() {
+            Some(meta) => meta.clone(),
+            None => return Ok(response),
+        };
+        let next_id = match request.route() {
+            Some((key, _)) => meta.next_hop(key),
+            None => meta.block_id,
+        };
+        println!("Redirected from block {:?} to {:?}", block_id, next_id);
+        bp_tree.learn_route(meta);
+        block_id = next_id;
+        request.set_block_id(next_id);
+    }
+    forward_to_providers(client, block_id, request).await //let the receiver route the request
+}
+
+//runs a tree operation on the blocking pool. writes sync the write-ahead log before they return and hold
+//block latches meanwhile, so neither they nor the readers waiting for those latches stall the runtime threads
+pub async fn blocking<K: TreeKey, V: TreeValue, R: Send + 'static>(
+    bp_tree: &Arc<BPTree<K, V>>,
+    f: impl FnOnce(&BPTree<K, V>) -> R + Send + 'static,
+) -> R {
+    let bp_tree = bp_tree.clone();
+    tokio::task::spawn_blocking(move || f(&bp_tree))
+        .await
+        .unwrap() //the operation panicked
+}
+
+async fn send_request<K: TreeKey, V: TreeValue>(
+    client: &mut Client,
+    peer: PeerId,
+    request: GeneralRequest<K, V>,
+) -> GeneralResponse<K, V> {
+    client
+        .request(peer, request)
+        .await
+        .and_then(|str| parse_response(peer, &str))
+        .unwrap_or_else(GeneralResponse::Error)
+}
+
+//wraps the response of a peer so the caller can see where the request was forwarded to
+fn parse_response<K: TreeKey, V: TreeValue>(
+    peer: PeerId,
+    str: &str,
+) -> Result<GeneralResponse<K, V>, NodeError> {
+    match serde_json::from_str(str) {
+        Ok(response) => Ok(GeneralResponse::Forwarded(peer, Box::new(response))),
+        Err(err) => Err(NodeError::Codec(err.to_string())),
+    }
+}
+
+fn unexpected<K: TreeKey, V: TreeValue>(response: GeneralResponse<K, V>) -> NodeError {
+    NodeError::Codec(format!("unexpected response {:?}", response))
+}
+
+fn unexpected_request<K: TreeKey, V: TreeValue>(request: GeneralRequest<K, V>) -> NodeError {
+    NodeError::Codec(format!("unexpected request {:?}", request))
 }
@@ -0,0 +1,24 @@
+use super::*;
+use futures::channel::mpsc;
+use tokio::time;
+
+pub async fn new() -> Result<(mpsc::Receiver<String>, LeaseLoop), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel(0);
+
+    Ok((receiver, LeaseLoop::new(sender)))
+}
+pub struct LeaseLoop {
+    sweep_sender: mpsc::Sender<String>,
+}
+impl LeaseLoop {
+    fn new(sweep_sender: mpsc::Sender<String>) -> Self {
+        Self { sweep_sender }
+    }
+    pub async fn run(mut self) {
+        loop {
+            let five_sec = time::Duration::from_millis(5000);
+            time::sleep(five_sec).await;
+            let _ = self.sweep_sender.send("Sweep".to_string()).await;
+        }
+    }
+}
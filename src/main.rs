@@ -7,21 +7,39 @@ use libp2p::request_response::ResponseChannel;
 use network::{Client, GenericResponse};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::thread;
 use tokio;
 use tokio::io::AsyncBufReadExt;
 use tokio::spawn;
 mod events;
-use events::{handle_insert_on_remote_parent, handle_lease_request, handle_migrate};
+use events::{
+    blocking, check_tree, claim_id_prefix, handle_abort_migration, handle_bulk_load,
+    handle_commit_migration, handle_compare_and_swap, handle_expire_leases, handle_get_lease,
+    handle_insert_on_remote_parent, handle_lease_request, handle_migrate, handle_migrate_blocks,
+    handle_range_scan, handle_release_lease, handle_renew_lease, handle_set_parent, range_scan,
+    resume_handoffs,
+};
+mod bench;
 mod bplus;
+mod error;
+mod migration;
 mod network;
-use bplus::{BPTree, Block, BlockId, Entry, Key};
+mod storage;
+use bplus::{
+    BPTree, Block, BlockId, BlockMeta, CasResult, Data, Entry, Key, DEFAULT_ORDER, LEASE_TTL,
+    MAX_PAYLOAD, MIN_ORDER,
+};
+use error::NodeError;
+use migration::Migrations;
+use storage::{FileStorage, MemoryStorage, Storage};
 mod gossip_timer;
+mod lease_timer;
+
+const SIZE_TOPIC: &str = "size"; //peers gossip the size of their block map to the root peer
 
 // run with cargo run -- --secret-key-seed #
 
@@ -39,6 +57,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let (mut network_client, mut network_events, network_event_loop, network_client_id) =
         network::new(secret_key_seed).await?;
     let (mut gossip_command, gossip_timer_loop) = gossip_timer::new().await?;
+    let (mut sweep_command, lease_timer_loop) = lease_timer::new().await?;
 
     // Spawn the network task for it to run in the background.
     spawn(network_event_loop.run());
@@ -46,6 +65,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     //spawn gossip timer
     spawn(gossip_timer_loop.run());
 
+    //spawn lease sweeper timer
+    spawn(lease_timer_loop.run());
+
     // In case a listen address was provided use it, otherwise listen on any
     // address.
     // match opt.listen_address {
@@ -73,18 +95,52 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .expect("Dial to succeed");
     }
 
-    let bp_tree = Arc::new(RwLock::new(BPTree::new())); //initialize bp_tree
-    let mut is_root = false;
-
-    let topic = Topic::new("size");
+    //peers started with a seed keep their blocks in a directory named after it
+    let storage_dir = opt
+        .storage_dir
+        .clone()
+        .or_else(|| secret_key_seed.map(|seed| PathBuf::from(format!("storage/peer-{}", seed))));
+    let storage: Box<dyn Storage> = match storage_dir {
+        Some(dir) => Box::new(FileStorage::new(dir)?),
+        None => Box::new(MemoryStorage),
+    };
+    if opt.leaf_order < MIN_ORDER || opt.internal_order < MIN_ORDER {
+        return Err(format!("block order must be at least {}", MIN_ORDER).into());
+    }
+    let mut tree = BPTree::open(storage, opt.leaf_order, opt.internal_order)?; //initialize bp_tree from storage
+    tree.set_max_payload(opt.max_payload);
+    tree.set_creator(&network_client_id); //block ids are derived from the peer id and a local counter
+    let bp_tree = Arc::new(tree); //blocks are latched one by one inside the tree
+    claim_id_prefix(&bp_tree, &mut network_client, network_client_id).await?;
+
+    let topic = Topic::new(SIZE_TOPIC);
+
+    //advertise the blocks kept from a previous run, but the ones it was handing off until their handoff is decided
+    let (stored_blocks, top_id) = (bp_tree.block_ids(), bp_tree.get_top_id());
+    let sending: Vec<BlockId> = bp_tree.sending().into_iter().map(|(id, _)| id).collect();
+    for id in stored_blocks.iter().filter(|id| !sending.contains(id)) {
+        network_client.start_providing(id.to_string()).await;
+    }
+    if top_id != 0 && stored_blocks.contains(&top_id) && !sending.contains(&top_id) {
+        network_client.boot_root().await;
+        network_client.subscribe(topic.clone()).await; //subscribe to gossipsub topic
+    }
+    if !stored_blocks.is_empty() {
+        println!("Loaded {:?} blocks from storage", stored_blocks.len());
+    }
 
     let mut migrate_peer = network_client_id;
     let mut cur_peer_size = f32::INFINITY as usize;
     let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
 
-    let migrating_block: Arc<RwLock<HashSet<BlockId>>> = Arc::new(RwLock::new(HashSet::new())); //keeping track of blocks that are in the progress of migration
-    let queries: Arc<RwLock<HashMap<BlockId, Vec<GeneralRequest>>>> =
-        Arc::new(RwLock::new(HashMap::new())); //storing commands to send after migration is complete
+    let migrations: Migrations = Migrations::new(); //blocks in the progress of migration and the requests to send once it is complete
+    if !sending.is_empty() {
+        let (mut clone_client, bp_tree, migrations) =
+            (network_client.clone(), bp_tree.clone(), migrations.clone());
+        spawn(async move {
+            resume_handoffs(&mut clone_client, bp_tree, migrations).await;
+        });
+    }
 
     loop {
         tokio::select! {
@@ -106,46 +162,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                         let input = line.parse::<u64>();
                                         match input{
                                             Ok(key) =>{
-                                                let entry = Entry::new(network_client_id,key);
-                                                if is_root{ //it is the provider of the root
-                                                    let read_top_id = bp_tree.read().unwrap();
-                                                    let top_id = read_top_id.get_top_id();
-                                                    let bp_tree = bp_tree.clone();
-                                                    let migrating_block = migrating_block.clone();
-                                                    let queries = queries.clone();
-                                                    let mut clone_client = network_client.clone();
-                                                    thread::spawn(move ||{
-                                                        let rt = tokio::runtime::Runtime::new().unwrap();
-                                                        rt.block_on( handle_lease_request(key,entry,bp_tree,&mut clone_client,migrate_peer,migrating_block,queries,top_id));
-                                                    });
-                                                }   
-                                                else{
-                                                let providers = network_client.get_providers("root".to_string()).await;
-                                                if providers.is_empty() {
-                                                    return Err(format!("Could not find provider for leases.").into());
+                                                println!("Type value (optional):");
+                                                let data = match stdin.next_line().await {
+                                                    Ok(Some(value)) => Data::new(value.into_bytes()),
+                                                    _ => Data::empty(),
+                                                };
+                                                let entry = Entry::new(network_client_id,key,data,opt.lease_ttl);
+                                                let mut block_id = Default::default(); //default id forwards the request to the root provider
+                                                if bp_tree.is_root(){ //the insert starts from the local top block
+                                                    block_id = bp_tree.get_top_id();
                                                 }
-
-
-
-                                            let mut copy_network_client = network_client.clone();
-                                            let mut p = network_client_id;
-                                            for i in providers.iter(){
-                                                p = *i;
-                                            }
-                                            let id = Default::default();
-                                            let lease = GeneralRequest::LeaseRequest(key,entry,id);
-                                            let result = copy_network_client.request(p,lease).await;
-
-                                            match result{
-                                                Ok(result)=>{
-                                                    println!("Done");
-                                                },
-                                                Err(_)=>{
-                                                    println!("Error");
-                                                }
-                                            }
+                                                let bp_tree = bp_tree.clone();
+                                                let migrations = migrations.clone();
+                                                let mut clone_client = network_client.clone();
+                                                tokio::spawn(async move {
+                                                    let response = handle_lease_request(key,entry,bp_tree,&mut clone_client,migrate_peer,migrations,block_id).await;
+                                                    println!("Lease {:?}", response);
+                                                });
                                             }
-                                        }
 
                                             Err(_) =>{
                                                 println!("Incorrect Key")
@@ -160,39 +194,300 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             }
                         }
 
+                        },
+                        cmd if cmd.starts_with("lookup") => {
+                            println!("Type key:");
+                            let key = stdin.next_line().await;
+                            match key {
+                                Ok(Some(line)) => match line.parse::<u64>() {
+                                    Ok(key) => {
+                                        let mut block_id = Default::default(); //default id forwards the lookup to the root provider
+                                        if bp_tree.is_root(){ //the search starts from the local top block
+                                            block_id = bp_tree.get_top_id();
+                                        }
+                                        let bp_tree = bp_tree.clone();
+                                        let mut clone_client = network_client.clone();
+                                        tokio::spawn(async move {
+                                            let entry = handle_get_lease(key,bp_tree,&mut clone_client,block_id).await;
+                                            println!("Lease {:?}", entry);
+                                        });
+                                    },
+                                    Err(_) => println!("Incorrect Key"),
+                                },
+                                Ok(None) => println!("Missing Key"),
+                                Err(_) => println!("Error"),
+                            }
+                        },
+                        cmd if cmd.starts_with("range") => {
+                            println!("Type start key:");
+                            let start = stdin.next_line().await;
+                            println!("Type end key:");
+                            let end = stdin.next_line().await;
+                            match (start, end) {
+                                (Ok(Some(start)), Ok(Some(end))) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                                    (Ok(start), Ok(end)) => {
+                                        let mut block_id = Default::default(); //default id starts the scan on the root provider
+                                        if bp_tree.is_root(){
+                                            block_id = bp_tree.get_top_id();
+                                        }
+                                        let bp_tree = bp_tree.clone();
+                                        let mut clone_client = network_client.clone();
+                                        tokio::spawn(async move {
+                                            let entries = range_scan(start,end,bp_tree,&mut clone_client,block_id).await;
+                                            println!("Range {:?}", entries);
+                                        });
+                                    },
+                                    _ => println!("Incorrect Key"),
+                                },
+                                _ => println!("Missing Key"),
+                            }
+                        },
+                        cmd if cmd.starts_with("renew") || cmd.starts_with("release") => {
+                            let renew = cmd.starts_with("renew");
+                            println!("Type key:");
+                            let key = stdin.next_line().await;
+                            match key {
+                                Ok(Some(line)) => match line.parse::<u64>() {
+                                    Ok(key) => {
+                                        let mut block_id = Default::default(); //default id forwards the request to the root provider
+                                        if bp_tree.is_root(){
+                                            block_id = bp_tree.get_top_id();
+                                        }
+                                        let bp_tree = bp_tree.clone();
+                                        let mut clone_client = network_client.clone();
+                                        tokio::spawn(async move {
+                                            if renew{
+                                                let renewed = handle_renew_lease(key,network_client_id,bp_tree,&mut clone_client,block_id).await;
+                                                println!("Renewed {:?}", renewed);
+                                            }
+                                            else{
+                                                let released = handle_release_lease(key,network_client_id,bp_tree,&mut clone_client,block_id).await;
+                                                println!("Released {:?}", released);
+                                            }
+                                        });
+                                    },
+                                    Err(_) => println!("Incorrect Key"),
+                                },
+                                Ok(None) => println!("Missing Key"),
+                                Err(_) => println!("Error"),
+                            }
+                        },
+                        cmd if cmd.starts_with("cas") => {
+                            println!("Type key:");
+                            let key = match stdin.next_line().await {
+                                Ok(Some(line)) => match line.parse::<u64>() {
+                                    Ok(key) => key,
+                                    Err(_) => {
+                                        println!("Incorrect Key");
+                                        continue;
+                                    }
+                                },
+                                _ => {
+                                    println!("Missing Key");
+                                    continue;
+                                }
+                            };
+                            println!("Type expected version:");
+                            let expected_version = match stdin.next_line().await {
+                                Ok(Some(line)) => match line.parse::<u64>() {
+                                    Ok(version) => version,
+                                    Err(_) => {
+                                        println!("Incorrect Version");
+                                        continue;
+                                    }
+                                },
+                                _ => {
+                                    println!("Missing Version");
+                                    continue;
+                                }
+                            };
+                            println!("Type value:");
+                            let value = match stdin.next_line().await {
+                                Ok(Some(line)) => line,
+                                _ => String::new(),
+                            };
+                            let mut block_id = Default::default(); //default id forwards the request to the root provider
+                            if bp_tree.is_root(){
+                                block_id = bp_tree.get_top_id();
+                            }
+                            let bp_tree = bp_tree.clone();
+                            let mut clone_client = network_client.clone();
+                            tokio::spawn(async move {
+                                let swapped = handle_compare_and_swap(key,expected_version,Data::new(value.into_bytes()),bp_tree,&mut clone_client,block_id).await;
+                                println!("Compare and swap {:?}", swapped);
+                            });
                         },
                         cmd if cmd.starts_with("root") => {
                             let providers = network_client.get_providers("root".to_string()).await;
-                            if providers.is_empty() {
-                                let mut block = Block::new(); //initialize block
-                                block.set_block_id(); //initialize block id
+                            if providers.is_err() {
+                                //peers found since the start may share the id prefix
+                                if let Err(err) = claim_id_prefix(&bp_tree, &mut network_client, network_client_id).await {
+                                    println!("Error {}", err);
+                                    continue;
+                                }
+                                let mut block = Block::new(bp_tree.leaf_order()); //initialize block
+                                match bp_tree.next_block_id() {
+                                    Ok(id) => block.set_block_id(id), //initialize block id
+                                    Err(err) => {
+                                        println!("Error {}", err);
+                                        continue;
+                                    }
+                                }
                                 let top_id = block.return_id();
-                                let bp_tree = bp_tree.clone();
-                                let mut bp_tree = bp_tree.write().unwrap();
-                                bp_tree.add_block(top_id,block); //insert block in map
-                                bp_tree.set_top_id(top_id); //set the top id
+                                if let Err(err) = blocking(&bp_tree, move |tree| tree.add_block(top_id,block)).await { //insert block in map
+                                    println!("Error {}", err);
+                                    continue;
+                                }
+                                if let Err(err) = blocking(&bp_tree, move |tree| tree.set_top_id(top_id)).await { //set the top id
+                                    println!("Error {}", err);
+                                    continue;
+                                }
+                                network_client.start_providing(top_id.to_string()).await; //children point to it by id
                                 network_client.boot_root().await;
                                 network_client.subscribe(topic.clone()).await; //subscribe to gossipsub topic
-                                is_root = true;
                             }
                             else{
                                 println!("root already exists!")
                             }
                         },
+                        cmd if cmd.starts_with("bulkload") => {
+                            if !bp_tree.is_root(){
+                                println!("bulk load has to start on the root peer");
+                                continue;
+                            }
+                            println!("Type path of the sorted entries:");
+                            let path = match stdin.next_line().await {
+                                Ok(Some(line)) => line,
+                                _ => {
+                                    println!("Missing Path");
+                                    continue;
+                                }
+                            };
+                            let contents = match std::fs::read_to_string(path.trim()) {
+                                Ok(contents) => contents,
+                                Err(err) => {
+                                    println!("Error {}", err);
+                                    continue;
+                                }
+                            };
+                            //one entry per line: key and an optional value
+                            let entries: Result<Vec<(Key, Entry)>, _> = contents
+                                .lines()
+                                .filter(|line| !line.trim().is_empty())
+                                .map(|line| {
+                                    let (key, value) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+                                    key.parse::<u64>().map(|key| {
+                                        (key, Entry::new(network_client_id, key, Data::new(value.as_bytes().to_vec()), opt.lease_ttl))
+                                    })
+                                })
+                                .collect();
+                            let entries = match entries {
+                                Ok(entries) => entries,
+                                Err(_) => {
+                                    println!("Incorrect Key");
+                                    continue;
+                                }
+                            };
+                            let mut peers: Vec<PeerId> = network_client.get_closest_peer(network_client_id).await;
+                            if migrate_peer != network_client_id && !peers.contains(&migrate_peer) {
+                                peers.push(migrate_peer);
+                            }
+                            peers.retain(|peer| *peer != network_client_id);
+                            let bp_tree = bp_tree.clone();
+                            let migrations = migrations.clone();
+                            let mut clone_client = network_client.clone();
+                            tokio::spawn(async move {
+                                let response = handle_bulk_load(entries,bp_tree,&mut clone_client,peers,migrations).await;
+                                println!("Bulk loaded {:?}", response);
+                            });
+                        },
+                        cmd if cmd.starts_with("check") => {
+                            let violations = bp_tree.check_invariants();
+                            println!("Local violations {:?}", violations);
+                            let mut block_id = Default::default(); //default id starts the walk at the root provider
+                            if bp_tree.is_root(){
+                                block_id = bp_tree.get_top_id();
+                            }
+                            let bp_tree = bp_tree.clone();
+                            let mut clone_client = network_client.clone();
+                            tokio::spawn(async move {
+                                let violations = check_tree(bp_tree,&mut clone_client,block_id).await;
+                                println!("Tree violations {:?}", violations);
+                            });
+                        },
+                        cmd if cmd.starts_with("bench") => {
+                            //bench [threads] [inserts per thread]
+                            let mut args = cmd.split_whitespace().skip(1).map(|arg| arg.parse::<usize>());
+                            let (threads, inserts) = match (args.next().unwrap_or(Ok(4)), args.next().unwrap_or(Ok(10000))) {
+                                (Ok(threads), Ok(inserts)) if threads > 0 => (threads, inserts),
+                                _ => {
+                                    println!("Incorrect Arguments");
+                                    continue;
+                                }
+                            };
+                            let (leaf_order, internal_order) = (opt.leaf_order, opt.internal_order);
+                            thread::spawn(move ||{
+                                match bench::run(threads, inserts, leaf_order, internal_order) {
+                                    Ok(result) => {
+                                        println!("Global lock {:.0} inserts/s", result.ops_per_sec(result.global_lock));
+                                        println!("Block latches {:.0} inserts/s", result.ops_per_sec(result.latched));
+                                    }
+                                    Err(err) => println!("Error {}", err),
+                                }
+                            });
+                        },
+                        cmd if cmd.starts_with("stress") => {
+                            //stress [requests]
+                            if !bp_tree.is_root(){
+                                println!("stress has to start on the root peer");
+                                continue;
+                            }
+                            let requests = match cmd.split_whitespace().nth(1).map(|arg| arg.parse::<usize>()) {
+                                None => 200,
+                                Some(Ok(requests)) => requests,
+                                Some(Err(_)) => {
+                                    println!("Incorrect Arguments");
+                                    continue;
+                                }
+                            };
+                            let top_id = bp_tree.get_top_id();
+                            let (bp_tree, migrations, clone_client) = (bp_tree.clone(), migrations.clone(), network_client.clone());
+                            tokio::spawn(async move {
+                                let result = bench::stress(requests,bp_tree,clone_client,network_client_id,migrate_peer,migrations,top_id).await;
+                                println!("Stress granted {:?} failed {:?} hung {:?}", result.granted, result.failed, result.hung);
+                            });
+                        },
                         cmd if cmd.starts_with("migrate") => {
-                                let bp_tree = bp_tree.read().unwrap();
-                                let id = bp_tree.get_top_id();
-                                let block = bp_tree.get_block(id).clone();
-                                let migrate_request = GeneralRequest::MigrateRequest(block);
-                                let result = network_client.request(migrate_peer,migrate_request).await;
-                                match result{
-                                    Ok(str) => {
-                                        let response:GeneralResponse= serde_json::from_str(&str).unwrap();
-                                        println!("New Provider {:?}", response);
-                                    },
-                                    Err(err) => println!("Error {:?}", err),
-                                };
-
+                            //migrate [block id] [peer] [subtree], the top block and the migrate peer by default
+                            let mut args = cmd.split_whitespace().skip(1);
+                            let id = match args.next().map(|arg| arg.parse::<BlockId>()) {
+                                None => bp_tree.get_top_id(),
+                                Some(Ok(id)) => id,
+                                Some(Err(_)) => {
+                                    println!("Incorrect Arguments");
+                                    continue;
+                                }
+                            };
+                            let peer = match args.next().map(|arg| arg.parse::<PeerId>()) {
+                                None => migrate_peer,
+                                Some(Ok(peer)) => peer,
+                                Some(Err(_)) => {
+                                    println!("Incorrect Arguments");
+                                    continue;
+                                }
+                            };
+                            let subtree = args.next() == Some("subtree");
+                            if peer == network_client_id {
+                                println!("block {} is already on this peer", id);
+                                continue;
+                            }
+                            let (bp_tree, migrations, mut clone_client) = (bp_tree.clone(), migrations.clone(), network_client.clone());
+                            tokio::spawn(async move {
+                                match handle_migrate_blocks(id,subtree,&mut clone_client,peer,bp_tree,migrations).await {
+                                    Ok(moved) => println!("New Provider {:?} of {:?}", peer, moved),
+                                    Err(err) => println!("Error {}", err),
+                                }
+                            });
                         }
 
                         _ => println!("unknown command\n"),
@@ -204,56 +499,194 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 None => {
                 },
                 Some(_) => {
-                   
-                    let tree_size = bp_tree.read().unwrap();
-                    let size = tree_size.get_size();
+
+                    let size = bp_tree.get_size();
                     network_client.publish(topic.clone(), size).await;
                 }
             },
+            sweep = sweep_command.next() => match sweep{ //expires leases in the local blocks
+                None => {
+                },
+                Some(_) => {
+                    let (copy_bp_tree, mut clone_client, migrations) = (bp_tree.clone(), network_client.clone(), migrations.clone());
+                    tokio::spawn(async move {
+                        handle_expire_leases(copy_bp_tree, &mut clone_client, migrations).await;
+                    });
+                }
+            },
             event = network_events.next() => match event {
                     None => {
                     },
                     Some(network::Event::InboundRequest {request, channel }) => {
 
-                        let response:GeneralRequest= serde_json::from_str(&request).unwrap();
+                        let response:GeneralRequest= match serde_json::from_str(&request){
+                            Ok(response) => response,
+                            Err(err) => {
+                                let error: GeneralResponse = GeneralResponse::Error(NodeError::Codec(err.to_string()));
+                                network_client.respond(error, channel).await;
+                                continue;
+                            }
+                        };
+                        //a request routed with an outdated view of its target block gets the current metadata back
+                        let response = match response{
+                            GeneralRequest::Routed{generation,request} => {
+                                let stale = request.route()
+                                    .and_then(|(_,block_id)| bp_tree.get_block(block_id).ok())
+                                    .filter(|block| block.generation() > generation);
+                                if let Some(block) = stale {
+                                    let redirect: GeneralResponse = GeneralResponse::Redirect(block.meta());
+                                    network_client.respond(redirect, channel).await;
+                                    continue;
+                                }
+                                *request
+                            }
+                            request => request,
+                        };
+                        //a peer the block migrated away from is still found through stale provider records, it says so
+                        //and the sender tries the next provider
+                        let missing = response.target().filter(|id| if *id == 0 { !bp_tree.is_root() } else { !bp_tree.is_local(*id) });
+                        if let Some(id) = missing {
+                            let error: GeneralResponse = GeneralResponse::Error(NodeError::MissingBlock(id));
+                            network_client.respond(error, channel).await;
+                            continue;
+                        }
                         let copy_bp_tree = bp_tree.clone();
                         let mut clone_client = network_client.clone();
-                        let migrating_block = migrating_block.clone();
-                        let queries = queries.clone();
+                        let migrations = migrations.clone();
                         match response{
                             GeneralRequest::LeaseRequest(key,entry,block_id) => { //request response channel
                                 let mut current_id = block_id;
-                                if (is_root){
-                                    let read_id = bp_tree.read().unwrap();
-                                    current_id = read_id.get_top_id();
+                                if bp_tree.is_root() && block_id == 0 { //the default id stands for the top block
+                                    current_id = bp_tree.get_top_id();
                                 }
-                                tokio::spawn(async move ||{
-                                    clone_client.respond(GeneralResponse::LeaseResponse, channel).await;
-                                    handle_lease_request(key, entry, copy_bp_tree,&mut clone_client,migrate_peer,
-                                        migrating_block,queries,current_id).await;
+                                tokio::spawn(async move {
+                                    let response = handle_lease_request(key, entry, copy_bp_tree,&mut clone_client,migrate_peer,
+                                        migrations,current_id).await;
+                                    clone_client.respond(response, channel).await; //granted or denied
                                 });
 
                             }
                             GeneralRequest::MigrateRequest(block)=>{
-                                tokio::spawn(async move||{ //get shared reference
-                                clone_client.respond(GeneralResponse::MigrateResponse, channel).await;
-                                handle_migrate(block,copy_bp_tree,&mut clone_client).await;
+                                tokio::spawn(async move { //get shared reference
+                                let response = handle_migrate(block,copy_bp_tree,&mut clone_client).await;
+                                clone_client.respond(response, channel).await;
                                 });
                             }
                             GeneralRequest::InsertOnRemoteParent(divider_key,parent_id,child_id) =>{
-                                tokio::spawn(async move||{
-                                clone_client.respond(GeneralResponse::InsertOnRemoteParent, channel).await;
-                                handle_insert_on_remote_parent(divider_key, parent_id,child_id, copy_bp_tree,
-                                &mut clone_client,migrate_peer,migrating_block,queries).await;
+                                tokio::spawn(async move {
+                                    let response = handle_insert_on_remote_parent(divider_key, parent_id,child_id, copy_bp_tree,
+                                    &mut clone_client,migrate_peer,migrations).await;
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::GetLease(key,block_id) => {
+                                let mut current_id = block_id;
+                                if bp_tree.is_root() && block_id == 0 { //the default id stands for the top block
+                                    current_id = bp_tree.get_top_id();
+                                }
+                                tokio::spawn(async move {
+                                    let response = match handle_get_lease(key,copy_bp_tree,&mut clone_client,current_id).await{
+                                        Ok(entry) => GeneralResponse::LeaseFound(entry),
+                                        Err(err) => GeneralResponse::Error(err),
+                                    };
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::RenewLease(key,holder,block_id) => {
+                                let mut current_id = block_id;
+                                if bp_tree.is_root() && block_id == 0 { //the default id stands for the top block
+                                    current_id = bp_tree.get_top_id();
+                                }
+                                tokio::spawn(async move {
+                                    let response: GeneralResponse = match handle_renew_lease(key,holder,copy_bp_tree,&mut clone_client,current_id).await{
+                                        Ok(renewed) => GeneralResponse::LeaseRenewed(renewed),
+                                        Err(err) => GeneralResponse::Error(err),
+                                    };
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::ReleaseLease(key,holder,block_id) => {
+                                let mut current_id = block_id;
+                                if bp_tree.is_root() && block_id == 0 { //the default id stands for the top block
+                                    current_id = bp_tree.get_top_id();
+                                }
+                                tokio::spawn(async move {
+                                    let response: GeneralResponse = match handle_release_lease(key,holder,copy_bp_tree,&mut clone_client,current_id).await{
+                                        Ok(released) => GeneralResponse::LeaseReleased(released),
+                                        Err(err) => GeneralResponse::Error(err),
+                                    };
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::CompareAndSwap{key,expected_version,new_data,block_id} => {
+                                let mut current_id = block_id;
+                                if bp_tree.is_root() && block_id == 0 { //the default id stands for the top block
+                                    current_id = bp_tree.get_top_id();
+                                }
+                                tokio::spawn(async move {
+                                    let response: GeneralResponse = match handle_compare_and_swap(key,expected_version,new_data,copy_bp_tree,&mut clone_client,current_id).await{
+                                        Ok(result) => GeneralResponse::CompareAndSwapped(result),
+                                        Err(err) => GeneralResponse::Error(err),
+                                    };
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::CommitMigration(block_id,is_top) => {
+                                tokio::spawn(async move {
+                                    let response = handle_commit_migration(block_id, is_top, copy_bp_tree, &mut clone_client).await;
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::SetParent(child_id,expected_id,parent_id) => {
+                                tokio::spawn(async move {
+                                    let response = handle_set_parent(child_id, expected_id, parent_id, copy_bp_tree, migrations).await;
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::AbortMigration(block_id) => {
+                                tokio::spawn(async move {
+                                    let response = handle_abort_migration(block_id, copy_bp_tree, &mut clone_client).await;
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::GetBlock(block_id) => {
+                                let mut current_id = block_id;
+                                if bp_tree.is_root() && block_id == 0 {
+                                    current_id = bp_tree.get_top_id();
+                                }
+                                tokio::spawn(async move {
+                                    let response: GeneralResponse = match blocking(&copy_bp_tree, move |tree| tree.get_block(current_id)).await {
+                                        Ok(block) => GeneralResponse::BlockFound(block),
+                                        Err(err) => GeneralResponse::Error(err),
+                                    };
+                                    clone_client.respond(response, channel).await;
+                                });
+                            }
+                            GeneralRequest::Routed{..} => {
+                                let error: GeneralResponse = GeneralResponse::Error(NodeError::Codec("nested routed request".to_string()));
+                                tokio::spawn(async move {
+                                    clone_client.respond(error, channel).await;
+                                });
+                            }
+                            GeneralRequest::RangeScan{start,end,cursor} => {
+                                let mut current_id = cursor;
+                                if bp_tree.is_root() && cursor == 0 {
+                                    current_id = bp_tree.get_top_id();
+                                }
+                                tokio::spawn(async move {
+                                    let (entries,cursor) = handle_range_scan(start,end,copy_bp_tree,current_id).await;
+                                    clone_client.respond(GeneralResponse::RangeResult{entries,cursor}, channel).await;
                                 });
                             }
                         }
                     },
                     Some(network::Event::InboundGossip{message}) => {
 
-                        let source_id = message.source.unwrap();
                         let data = String::from_utf8_lossy(&message.data);
-                        let peer_size:usize = serde_json::from_str(&data).unwrap();
+                        let (source_id, peer_size):(PeerId, usize) = match (message.source, serde_json::from_str(&data)){
+                            (Some(source_id), Ok(peer_size)) => (source_id, peer_size),
+                            _ => continue, //unsigned or malformed gossip
+                        };
                         if peer_size<cur_peer_size{
                             cur_peer_size = peer_size;
                             migrate_peer = source_id;
@@ -276,6 +709,26 @@ struct Opt {
     /// Fixed value to generate deterministic peer ID.
     #[clap(long)]
     secret_key_seed: Option<u8>,
+
+    /// Duration of a granted lease in seconds.
+    #[clap(long, default_value_t = LEASE_TTL)]
+    lease_ttl: u64,
+
+    /// Directory the local blocks are stored in, defaults to storage/peer-<seed> when a seed is given.
+    #[clap(long)]
+    storage_dir: Option<PathBuf>,
+
+    /// Number of keys at which a leaf created by this peer splits.
+    #[clap(long, default_value_t = DEFAULT_ORDER)]
+    leaf_order: usize,
+
+    /// Number of keys at which an internal block created by this peer splits.
+    #[clap(long, default_value_t = DEFAULT_ORDER)]
+    internal_order: usize,
+
+    /// Largest lease payload in bytes accepted by this peer.
+    #[clap(long, default_value_t = MAX_PAYLOAD)]
+    max_payload: usize,
     // #[clap(long)]
     // peer: Option<Multiaddr>,
 
@@ -287,16 +740,117 @@ struct Opt {
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
-pub enum GeneralRequest {
-    LeaseRequest(Key, Entry,BlockId),
-    MigrateRequest(Block),
-    InsertOnRemoteParent(Key, BlockId, BlockId),
+pub enum GeneralRequest<K = Key, V = Data> {
+    LeaseRequest(K, Entry<K, V>, BlockId),
+    MigrateRequest(Block<K, V>), //prepare: the receiver stores and advertises the block, frozen
+    CommitMigration(BlockId, bool), //the receiver takes the prepared block over, and the root with it if true
+    AbortMigration(BlockId),        //the receiver drops the prepared block
+    SetParent(BlockId, BlockId, BlockId), //child, the parent it is expected to point to and the parent that took it over
+    InsertOnRemoteParent(K, BlockId, BlockId),
+    GetLease(K, BlockId),
+    RangeScan {
+        start: K,
+        end: K,
+        cursor: BlockId,
+    },
+    RenewLease(K, PeerId, BlockId),
+    ReleaseLease(K, PeerId, BlockId),
+    CompareAndSwap {
+        key: K,
+        expected_version: u64,
+        new_data: V,
+        block_id: BlockId,
+    },
+    GetBlock(BlockId),
+    Routed {
+        generation: u64, //generation of the target block the sender routed with
+        request: Box<GeneralRequest<K, V>>,
+    },
+}
+impl<K, V> GeneralRequest<K, V> {
+    //key and target block of the requests that are routed down the tree by key
+    pub fn route(&self) -> Option<(&K, BlockId)> {
+        match self {
+            GeneralRequest::LeaseRequest(key, _, block_id)
+            | GeneralRequest::GetLease(key, block_id)
+            | GeneralRequest::RenewLease(key, _, block_id)
+            | GeneralRequest::ReleaseLease(key, _, block_id)
+            | GeneralRequest::CompareAndSwap { key, block_id, .. } => Some((key, *block_id)),
+            GeneralRequest::Routed { request, .. } => request.route(),
+            _ => None,
+        }
+    }
+    //block the receiver has to hold to handle the request
+    pub fn target(&self) -> Option<BlockId> {
+        match self {
+            GeneralRequest::InsertOnRemoteParent(_, parent, _) => Some(*parent),
+            GeneralRequest::RangeScan { cursor, .. } => Some(*cursor),
+            GeneralRequest::GetBlock(block_id) => Some(*block_id),
+            GeneralRequest::SetParent(child, _, _) => Some(*child),
+            request => request.route().map(|(_, block_id)| block_id),
+        }
+    }
+    pub fn set_block_id(&mut self, id: BlockId) {
+        match self {
+            GeneralRequest::LeaseRequest(_, _, block_id)
+            | GeneralRequest::GetLease(_, block_id)
+            | GeneralRequest::RenewLease(_, _, block_id)
+            | GeneralRequest::ReleaseLease(_, _, block_id)
+            | GeneralRequest::CompareAndSwap { block_id, .. } => *block_id = id,
+            GeneralRequest::Routed { request, .. } => request.set_block_id(id),
+            _ => {}
+        }
+    }
 }
 #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
-pub enum GeneralResponse {
-    LeaseResponse,
-    MigrateResponse,
-    InsertOnRemoteParent,
+pub enum GeneralResponse<K = Key, V = Data> {
+    LeaseGranted(BlockId),
+    Prepared(BlockId),
+    Migrated(BlockId),
+    Aborted(BlockId),
+    ChildInserted(BlockId), //block that took the child
+    ParentSet(BlockId),
+    LeaseFound(Option<Entry<K, V>>),
+    RangeResult {
+        entries: Vec<(K, Entry<K, V>)>,
+        cursor: Option<BlockId>,
+    },
+    LeaseRenewed(bool),
+    LeaseReleased(bool),
+    CompareAndSwapped(CasResult),
+    BlockFound(Block<K, V>),
+    Redirect(BlockMeta<K>), //the sender routed with an older generation of the block
+    LeaseDenied {
+        holder: PeerId,
+        expires_at: u64,
+    },
+    Forwarded(PeerId, Box<GeneralResponse<K, V>>), //response of the peer the request was forwarded to
+    Error(NodeError),
+}
+impl<K, V> GeneralResponse<K, V> {
+    //outcome at the end of the forwarding chain
+    pub fn result(self) -> GeneralResponse<K, V> {
+        match self {
+            GeneralResponse::Forwarded(_, response) => response.result(),
+            response => response,
+        }
+    }
+    //true if the peer answered that it does not hold the block
+    pub fn is_missing(&self, id: BlockId) -> bool {
+        matches!(self, GeneralResponse::Error(NodeError::MissingBlock(missing)) if *missing == id)
+    }
+    //true if the peer holds the block frozen, in a migration handoff
+    pub fn is_migrating(&self, id: BlockId) -> bool {
+        matches!(self, GeneralResponse::Error(NodeError::BlockMigrating(migrating)) if *migrating == id)
+    }
+    //fresh block metadata if the request has to be routed again
+    pub fn redirect(&self) -> Option<&BlockMeta<K>> {
+        match self {
+            GeneralResponse::Forwarded(_, response) => response.redirect(),
+            GeneralResponse::Redirect(meta) => Some(meta),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Parser)]
@@ -0,0 +1,71 @@
+use crate::bplus::{BlockId, Data, Key, TreeKey, TreeValue};
+use crate::{GeneralRequest, GeneralResponse};
+use futures::channel::oneshot;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+//requests waiting for a block to finish migrating, with the channel to answer them on
+pub type PendingQueries<K = Key, V = Data> =
+    HashMap<BlockId, Vec<(GeneralRequest<K, V>, oneshot::Sender<GeneralResponse<K, V>>)>>;
+
+//blocks being migrated away and the requests queued for them, shared by the event handlers.
+//every method takes the lock and releases it before returning, so it is never held across a network call,
+//and checking a block and queueing a request for it happen under the same lock
+#[derive(Clone)]
+pub struct Migrations<K = Key, V = Data> {
+    state: Arc<Mutex<MigrationState<K, V>>>,
+}
+
+struct MigrationState<K, V> {
+    blocks: HashSet<BlockId>,
+    queries: PendingQueries<K, V>,
+}
+
+impl<K: TreeKey, V: TreeValue> Default for Migrations<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: TreeKey, V: TreeValue> Migrations<K, V> {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MigrationState {
+                blocks: HashSet::new(),
+                queries: HashMap::new(),
+            })),
+        }
+    }
+    //marks a block as migrating, false if it already is
+    pub fn begin(&self, id: BlockId) -> bool {
+        self.state.lock().unwrap().blocks.insert(id)
+    }
+    //blocks migrating right now
+    pub fn blocks(&self) -> HashSet<BlockId> {
+        self.state.lock().unwrap().blocks.clone()
+    }
+    //queues a request for a migrating block and returns where its answer arrives,
+    //none when the block is not migrating and the caller handles the request itself
+    pub fn queue(
+        &self,
+        id: BlockId,
+        request: GeneralRequest<K, V>,
+    ) -> Option<oneshot::Receiver<GeneralResponse<K, V>>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.blocks.contains(&id) {
+            return None;
+        }
+        let (sender, receiver) = oneshot::channel();
+        state.queries.entry(id).or_default().push((request, sender));
+        Some(receiver)
+    }
+    //ends the migration of a block and hands back the requests queued for it
+    pub fn finish(
+        &self,
+        id: BlockId,
+    ) -> Vec<(GeneralRequest<K, V>, oneshot::Sender<GeneralResponse<K, V>>)> {
+        let mut state = self.state.lock().unwrap();
+        state.blocks.remove(&id);
+        state.queries.remove(&id).unwrap_or_default()
+    }
+}
@@ -16,8 +16,8 @@ use libp2p::kad::{
 use libp2p::mdns::{Mdns, MdnsConfig, MdnsEvent};
 use libp2p::multiaddr::Protocol;
 use libp2p::request_response::{
-    ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec, RequestResponseEvent,
-    RequestResponseMessage, ResponseChannel,
+    OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec,
+    RequestResponseEvent, RequestResponseMessage, ResponseChannel,
 };
 use libp2p::swarm::{ConnectionHandlerUpgrErr, SwarmBuilder, SwarmEvent};
 use libp2p::{gossipsub, identity};
@@ -99,6 +99,7 @@ pub async fn new(
     Ok((
         Client {
             sender: command_sender,
+            peer_id,
         },
         event_receiver,
         EventLoop::new(swarm, command_receiver, event_sender),
@@ -109,9 +110,15 @@ pub async fn new(
 #[derive(Clone)]
 pub struct Client {
     sender: mpsc::Sender<Command>,
+    peer_id: PeerId,
 }
 
 impl Client {
+    /// The peer this client sends from.
+    pub fn local_peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
     /// Listen for incoming connections on the given address.
 
     pub async fn start_listening(
@@ -154,23 +161,31 @@ impl Client {
             .expect("Command receiver not to be dropped.");
         receiver.await.expect("Sender not to be dropped.");
     }
-    //Stop providing
+    /// Withdraw the local provider record of the given file. Peers that already stored the record
+    /// keep it until it expires, so callers still have to expect providers that no longer hold it.
     pub async fn stop_providing(&mut self, file_name: String) {
-        // let (sender, receiver) = oneshot::channel();
-        // self.sender
-        //     .send(Command::StopProviding { file_name,sender})
-        //     .await
-        //     .expect("Command receiver not to be dropped.");
-        // receiver.await.expect("Sender not to be dropped.");
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::StopProviding { file_name, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.");
     }
     /// Find the providers for the given file on the DHT.
-    pub async fn get_providers(&mut self, file_name: String) -> HashSet<PeerId> {
+    pub async fn get_providers(&mut self, file_name: String) -> Result<HashSet<PeerId>, NodeError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
-            .send(Command::GetProviders { file_name, sender })
+            .send(Command::GetProviders {
+                file_name: file_name.clone(),
+                sender,
+            })
             .await
             .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+        let providers = receiver.await.expect("Sender not to be dropped.");
+        if providers.is_empty() {
+            return Err(NodeError::NoProvider(file_name));
+        }
+        Ok(providers)
     }
 
     pub async fn get_closest_peer(&mut self, id: PeerId) -> Vec<PeerId> {
@@ -182,11 +197,13 @@ impl Client {
         receiver.await.expect("Sender not to be dropped.")
     }
 
-    pub async fn request(
+    pub async fn request<R: Serialize>(
         &mut self,
         peer: PeerId,
-        request: GeneralRequest,
-    ) -> Result<String, Box<dyn Error + Send>> {
+        request: R,
+    ) -> Result<String, NodeError> {
+        let request =
+            serde_json::to_string(&request).map_err(|err| NodeError::Codec(err.to_string()))?;
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(Command::Request {
@@ -199,11 +216,18 @@ impl Client {
         receiver.await.expect("Sender not be dropped.")
     }
 
-    pub async fn respond(
+    pub async fn respond<R: Serialize>(
         &mut self,
-        response: GeneralResponse,
+        response: R,
         channel: ResponseChannel<GenericResponse>,
     ) {
+        let response = match serde_json::to_string(&response) {
+            Ok(response) => response,
+            Err(err) => {
+                println!("Error {:?}", NodeError::Codec(err.to_string()));
+                return;
+            }
+        };
         self.sender
             .send(Command::Respond { response, channel })
             .await
@@ -240,16 +264,81 @@ impl Client {
     }
 }
 
+/// Answers the requests a client sends to a peer, in tests without a swarm.
+#[cfg(test)]
+pub type Handler = Arc<
+    dyn Fn(PeerId, String) -> future::BoxFuture<'static, Result<String, NodeError>> + Send + Sync,
+>;
+
+/// Creates a client whose commands are answered in process instead of by a swarm, for tests.
+///
+/// Provider records are kept in a map the test shares between the clients of all its peers,
+/// and every request is passed to the handler along with the peer it is sent to.
+#[cfg(test)]
+pub fn in_process(
+    peer: PeerId,
+    providers: Arc<std::sync::Mutex<HashMap<String, HashSet<PeerId>>>>,
+    handler: Handler,
+) -> Client {
+    let (command_sender, mut command_receiver) = mpsc::channel(0);
+    tokio::spawn(async move {
+        while let Some(command) = command_receiver.next().await {
+            match command {
+                Command::StartProviding { file_name, sender }
+                | Command::BootRoot {
+                    up_root: file_name,
+                    sender,
+                } => {
+                    let mut providers = providers.lock().unwrap();
+                    providers.entry(file_name).or_default().insert(peer);
+                    let _ = sender.send(());
+                }
+                Command::StopProviding { file_name, sender } => {
+                    if let Some(peers) = providers.lock().unwrap().get_mut(&file_name) {
+                        peers.remove(&peer);
+                    }
+                    let _ = sender.send(());
+                }
+                Command::GetProviders { file_name, sender } => {
+                    let peers = providers.lock().unwrap().get(&file_name).cloned();
+                    let _ = sender.send(peers.unwrap_or_default());
+                }
+                Command::Request {
+                    peer: to,
+                    request,
+                    sender,
+                } => {
+                    let response = handler(to, request);
+                    tokio::spawn(async move {
+                        let _ = sender.send(response.await);
+                    });
+                }
+                Command::StartListening { sender, .. } | Command::Dial { sender, .. } => {
+                    let _ = sender.send(Ok(())); //peers are reached through the handler
+                }
+                Command::GetClosestPeers { sender, .. } => {
+                    let _ = sender.send(Vec::new()); //no peers are discovered in process
+                }
+                //the handler answers every request itself, and gossip is not delivered
+                Command::Subscribe { .. } | Command::Publish { .. } | Command::Respond { .. } => {}
+            }
+        }
+    });
+    Client {
+        sender: command_sender,
+        peer_id: peer,
+    }
+}
+
 pub struct EventLoop {
     swarm: Swarm<ComposedBehaviour>,
     command_receiver: mpsc::Receiver<Command>,
     event_sender: mpsc::Sender<Event>,
     pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), Box<dyn Error + Send>>>>,
     pending_start_providing: HashMap<QueryId, oneshot::Sender<()>>,
-    pending_stop_providing: HashMap<QueryId, oneshot::Sender<()>>,
     pending_get_providers: HashMap<QueryId, oneshot::Sender<HashSet<PeerId>>>,
     pending_get_closest_peers: HashMap<QueryId, oneshot::Sender<Vec<PeerId>>>,
-    pending_request: HashMap<RequestId, oneshot::Sender<Result<String, Box<dyn Error + Send>>>>,
+    pending_request: HashMap<RequestId, oneshot::Sender<Result<String, NodeError>>>,
 }
 impl EventLoop {
     fn new(
@@ -263,7 +352,6 @@ impl EventLoop {
             event_sender,
             pending_dial: Default::default(),
             pending_start_providing: Default::default(),
-            pending_stop_providing: Default::default(),
             pending_get_providers: Default::default(),
             pending_request: Default::default(),
             pending_get_closest_peers: Default::default(),
@@ -351,11 +439,9 @@ impl EventLoop {
                     ..
                 },
             )) => {
-                let _ = self
-                    .pending_get_closest_peers
-                    .remove(&id)
-                    .expect("Completed query to be previously pending.")
-                    .send(peers);
+                if let Some(sender) = self.pending_get_closest_peers.remove(&id) {
+                    let _ = sender.send(peers);
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(
                 KademliaEvent::OutboundQueryCompleted {
@@ -364,11 +450,9 @@ impl EventLoop {
                     ..
                 },
             )) => {
-                let sender: oneshot::Sender<()> = self
-                    .pending_start_providing
-                    .remove(&id)
-                    .expect("Completed query to be previously pending.");
-                let _ = sender.send(());
+                if let Some(sender) = self.pending_start_providing.remove(&id) {
+                    let _ = sender.send(());
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(
                 KademliaEvent::OutboundQueryCompleted {
@@ -377,11 +461,21 @@ impl EventLoop {
                     ..
                 },
             )) => {
-                let _ = self
-                    .pending_get_providers
-                    .remove(&id)
-                    .expect("Completed query to be previously pending.")
-                    .send(providers);
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    let _ = sender.send(providers);
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryCompleted {
+                    id,
+                    result: QueryResult::GetProviders(Err(_)),
+                    ..
+                },
+            )) => {
+                //a failed lookup is reported to the client as no providers
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    let _ = sender.send(HashSet::new());
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(_)) => {}
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
@@ -402,23 +496,25 @@ impl EventLoop {
                     request_id,
                     response,
                 } => {
-                    let _ = self
-                        .pending_request
-                        .remove(&request_id)
-                        .expect("Request to still be pending.")
-                        .send(Ok(response.0));
+                    if let Some(sender) = self.pending_request.remove(&request_id) {
+                        let _ = sender.send(Ok(response.0));
+                    }
                 }
             },
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
                 RequestResponseEvent::OutboundFailure {
-                    request_id, error, ..
+                    peer,
+                    request_id,
+                    error,
                 },
             )) => {
-                let _ = self
-                    .pending_request
-                    .remove(&request_id)
-                    .expect("Request to still be pending.")
-                    .send(Err(Box::new(error)));
+                let error = match error {
+                    OutboundFailure::Timeout => NodeError::Timeout(peer),
+                    _ => NodeError::PeerUnreachable(peer),
+                };
+                if let Some(sender) = self.pending_request.remove(&request_id) {
+                    let _ = sender.send(Err(error));
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
                 RequestResponseEvent::ResponseSent { .. },
@@ -450,7 +546,7 @@ impl EventLoop {
             }
             SwarmEvent::IncomingConnectionError { .. } => {}
             SwarmEvent::Dialing(peer_id) => println!("Dialing {}", peer_id),
-            e => panic!("{:?}", e),
+            e => println!("Unhandled swarm event {:?}", e),
         }
     }
 
@@ -503,10 +599,11 @@ impl EventLoop {
                 self.pending_start_providing.insert(query_id, sender);
             }
             Command::StopProviding { file_name, sender } => {
-                // let _ = match self.swarm.behaviour_mut().kademlia.stop_providing(file_name.into_bytes().into()) {
-                //     Ok(_) => sender.send(Ok(())),
-                //     Err(e) => sender.send(Err(Box::new(e))),
-                // };
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .stop_providing(&file_name.into_bytes().into());
+                let _ = sender.send(());
             }
             Command::GetProviders { file_name, sender } => {
                 let query_id = self
@@ -525,7 +622,6 @@ impl EventLoop {
                 request,
                 sender,
             } => {
-                let request = serde_json::to_string(&request).unwrap();
                 let request_id = self
                     .swarm
                     .behaviour_mut()
@@ -534,12 +630,15 @@ impl EventLoop {
                 self.pending_request.insert(request_id, sender);
             }
             Command::Respond { response, channel } => {
-                let response = serde_json::to_string(&response).unwrap();
-                self.swarm
+                if self
+                    .swarm
                     .behaviour_mut()
                     .request_response
                     .send_response(channel, GenericResponse(response))
-                    .expect("Connection to peer to be still open.");
+                    .is_err()
+                {
+                    println!("Connection to peer closed before the response was sent");
+                }
             }
 
             Command::BootRoot { up_root, sender } => {
@@ -633,7 +732,7 @@ enum Command {
     },
     StopProviding {
         file_name: String,
-        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+        sender: oneshot::Sender<()>,
     },
     GetProviders {
         file_name: String,
@@ -645,11 +744,11 @@ enum Command {
     },
     Request {
         peer: PeerId,
-        request: GeneralRequest,
-        sender: oneshot::Sender<Result<String, Box<dyn Error + Send>>>,
+        request: String,
+        sender: oneshot::Sender<Result<String, NodeError>>,
     },
     Respond {
-        response: GeneralResponse,
+        response: String,
         channel: ResponseChannel<GenericResponse>,
     },
     BootRoot {
@@ -719,7 +818,9 @@ impl RequestResponseCodec for GenericExchangeCodec {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        Ok(GenericRequest(String::from_utf8(vec).unwrap()))
+        String::from_utf8(vec)
+            .map(GenericRequest)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
     }
 
     async fn read_response<T>(
@@ -736,7 +837,9 @@ impl RequestResponseCodec for GenericExchangeCodec {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        Ok(GenericResponse(String::from_utf8(vec).unwrap()))
+        String::from_utf8(vec)
+            .map(GenericResponse)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
     }
 
     async fn write_request<T>(
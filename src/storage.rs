@@ -0,0 +1,218 @@
+use crate::bplus::{Block, BlockId, Data, Handoff, Key, TreeKey, TreeValue};
+use crate::error::NodeError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+//backend that keeps the local block map across restarts
+pub trait Storage<K = Key, V = Data>: Send + Sync {
+    fn load(&self) -> Result<(BlockId, Vec<Block<K, V>>), NodeError>;
+    fn write_block(&mut self, block: &Block<K, V>) -> Result<(), NodeError>;
+    fn delete_block(&mut self, id: BlockId) -> Result<(), NodeError>;
+    fn write_top_id(&mut self, id: BlockId) -> Result<(), NodeError>;
+    fn load_counter(&self) -> Result<u64, NodeError>;
+    fn write_counter(&mut self, counter: u64) -> Result<(), NodeError>;
+    fn load_handoffs(&self) -> Result<HashMap<BlockId, Handoff>, NodeError>;
+    fn write_handoffs(&mut self, handoffs: &HashMap<BlockId, Handoff>) -> Result<(), NodeError>;
+    fn append_log(&mut self, record: &LogRecord<K, V>) -> Result<(), NodeError>;
+    fn read_log(&self) -> Result<Vec<LogRecord<K, V>>, NodeError>;
+    fn checkpoint(&mut self) -> Result<(), NodeError>;
+}
+
+//the tree operation that produced a log record
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum LogOp<K> {
+    AddEntry(K),
+    RemoveEntry(K),
+    RenewEntry(K),
+    UpdateEntry(K),
+    AddChild(K, BlockId),
+    Split(BlockId, BlockId), //left and right block
+    MigrateIn(BlockId),
+    MigrateOut(BlockId),
+    SetTop(BlockId),
+    BulkLoad(BlockId),     //top block of the loaded tree
+    Rebalance(BlockId),    //underfull block that borrowed from or merged with a sibling
+    CollapseRoot(BlockId), //root removed in favour of its only child
+    SetParent(BlockId),    //block that learned its actual parent
+    Freeze(BlockId), //block sent to another peer, owned by neither until the handoff is decided
+    Thaw(BlockId),   //block whose handoff ended with this peer as its owner
+}
+
+//blocks as they are after the operation, written to the log before any block file is touched
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogRecord<K, V> {
+    pub op: LogOp<K>,
+    pub blocks: Vec<Block<K, V>>,
+    pub removed: Vec<BlockId>,
+    pub top_id: BlockId,
+    #[serde(default)]
+    pub counter: u64, //block id counter after the operation
+    #[serde(default)]
+    pub handoffs: HashMap<BlockId, Handoff>, //blocks in a migration handoff after the operation
+}
+
+//keeps nothing, used when the peer runs without a storage directory
+pub struct MemoryStorage;
+
+impl<K, V> Storage<K, V> for MemoryStorage {
+    fn load(&self) -> Result<(BlockId, Vec<Block<K, V>>), NodeError> {
+        Ok((Default::default(), Vec::new()))
+    }
+    fn write_block(&mut self, _block: &Block<K, V>) -> Result<(), NodeError> {
+        Ok(())
+    }
+    fn delete_block(&mut self, _id: BlockId) -> Result<(), NodeError> {
+        Ok(())
+    }
+    fn write_top_id(&mut self, _id: BlockId) -> Result<(), NodeError> {
+        Ok(())
+    }
+    fn load_counter(&self) -> Result<u64, NodeError> {
+        Ok(0)
+    }
+    fn write_counter(&mut self, _counter: u64) -> Result<(), NodeError> {
+        Ok(())
+    }
+    fn load_handoffs(&self) -> Result<HashMap<BlockId, Handoff>, NodeError> {
+        Ok(HashMap::new())
+    }
+    fn write_handoffs(&mut self, _handoffs: &HashMap<BlockId, Handoff>) -> Result<(), NodeError> {
+        Ok(())
+    }
+    fn append_log(&mut self, _record: &LogRecord<K, V>) -> Result<(), NodeError> {
+        Ok(())
+    }
+    fn read_log(&self) -> Result<Vec<LogRecord<K, V>>, NodeError> {
+        Ok(Vec::new())
+    }
+    fn checkpoint(&mut self) -> Result<(), NodeError> {
+        Ok(())
+    }
+}
+
+//stores every block as a json file named after its id, next to a write-ahead log
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(dir: PathBuf) -> Result<Self, NodeError> {
+        fs::create_dir_all(&dir).map_err(storage_error)?;
+        Ok(Self { dir })
+    }
+    fn block_path(&self, id: BlockId) -> PathBuf {
+        self.dir.join(format!("{}.block", id))
+    }
+    fn log_path(&self) -> PathBuf {
+        self.dir.join("wal")
+    }
+    fn write_file(&mut self, path: PathBuf, contents: String) -> Result<(), NodeError> {
+        //write to a temporary file and sync it before the rename, so a crash never leaves a half
+        //written block
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp).map_err(storage_error)?;
+        file.write_all(contents.as_bytes()).map_err(storage_error)?;
+        file.sync_all().map_err(storage_error)?;
+        fs::rename(&tmp, &path).map_err(storage_error)
+    }
+}
+
+impl<K: TreeKey, V: TreeValue> Storage<K, V> for FileStorage {
+    fn load(&self) -> Result<(BlockId, Vec<Block<K, V>>), NodeError> {
+        let top_id = match fs::read_to_string(self.dir.join("top")) {
+            Ok(top_id) => top_id.trim().parse().unwrap_or_default(),
+            Err(_) => Default::default(), //this peer never held the top of the tree
+        };
+        let mut blocks = Vec::new();
+        for file in fs::read_dir(&self.dir).map_err(storage_error)? {
+            let path = file.map_err(storage_error)?.path();
+            if path.extension().map_or(false, |ext| ext == "block") {
+                let contents = fs::read_to_string(&path).map_err(storage_error)?;
+                let block = serde_json::from_str(&contents)
+                    .map_err(|err| NodeError::Codec(err.to_string()))?;
+                blocks.push(block);
+            }
+        }
+        Ok((top_id, blocks))
+    }
+    fn write_block(&mut self, block: &Block<K, V>) -> Result<(), NodeError> {
+        let contents =
+            serde_json::to_string(block).map_err(|err| NodeError::Codec(err.to_string()))?;
+        self.write_file(self.block_path(block.return_id()), contents)
+    }
+    fn delete_block(&mut self, id: BlockId) -> Result<(), NodeError> {
+        match fs::remove_file(self.block_path(id)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(storage_error(err)),
+            _ => Ok(()),
+        }
+    }
+    fn write_top_id(&mut self, id: BlockId) -> Result<(), NodeError> {
+        self.write_file(self.dir.join("top"), id.to_string())
+    }
+    fn load_counter(&self) -> Result<u64, NodeError> {
+        match fs::read_to_string(self.dir.join("counter")) {
+            Ok(counter) => Ok(counter.trim().parse().unwrap_or_default()),
+            Err(_) => Ok(0), //no block was created on this peer yet
+        }
+    }
+    fn write_counter(&mut self, counter: u64) -> Result<(), NodeError> {
+        self.write_file(self.dir.join("counter"), counter.to_string())
+    }
+    fn load_handoffs(&self) -> Result<HashMap<BlockId, Handoff>, NodeError> {
+        match fs::read_to_string(self.dir.join("handoffs")) {
+            Ok(handoffs) => {
+                serde_json::from_str(&handoffs).map_err(|err| NodeError::Codec(err.to_string()))
+            }
+            Err(_) => Ok(HashMap::new()), //no block was in a handoff
+        }
+    }
+    fn write_handoffs(&mut self, handoffs: &HashMap<BlockId, Handoff>) -> Result<(), NodeError> {
+        let contents =
+            serde_json::to_string(handoffs).map_err(|err| NodeError::Codec(err.to_string()))?;
+        self.write_file(self.dir.join("handoffs"), contents)
+    }
+    fn append_log(&mut self, record: &LogRecord<K, V>) -> Result<(), NodeError> {
+        let mut line =
+            serde_json::to_string(record).map_err(|err| NodeError::Codec(err.to_string()))?;
+        line.push('\n');
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path())
+            .map_err(storage_error)?;
+        log.write_all(line.as_bytes()).map_err(storage_error)?;
+        log.sync_data().map_err(storage_error) //the record is on disk before the blocks change
+    }
+    fn read_log(&self) -> Result<Vec<LogRecord<K, V>>, NodeError> {
+        let contents = match fs::read_to_string(self.log_path()) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(storage_error(err)),
+        };
+        let mut records = Vec::new();
+        for line in contents.lines() {
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(_) => break, //a torn record at the end of the log was never applied
+            }
+        }
+        Ok(records)
+    }
+    fn checkpoint(&mut self) -> Result<(), NodeError> {
+        //the block files are synced as they are written, syncing the directory makes their renames
+        //and deletions durable, then the log is no longer needed to recover them
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(storage_error)?;
+        File::create(self.log_path())
+            .and_then(|log| log.sync_all())
+            .map_err(storage_error)
+    }
+}
+
+fn storage_error(err: std::io::Error) -> NodeError {
+    NodeError::Storage(err.to_string())
+}